@@ -0,0 +1,106 @@
+//! Pluggable transport for siteverify calls.
+
+use std::error::Error;
+
+use futures_util::future::BoxFuture;
+
+/// Error returned by an [`HttpBackend`].
+pub type BackendError = Box<dyn Error + Send + Sync>;
+
+/// A raw siteverify reply as received by an [`HttpBackend`].
+#[derive(Clone, Debug, Default)]
+pub struct BackendResponse {
+    pub status: u16,
+    /// Response headers; names may use any case.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// Sends siteverify requests through an HTTP client other than reqwest
+/// (e.g. hyper, ureq, or an in-process transport).
+///
+/// Set it as [`TurnstileConfig::http_backend`](crate::TurnstileConfig) to
+/// bypass `client`, `timeout_secs` and the shared reqwest client; the backend
+/// is expected to apply its own timeouts. Errors are treated like network
+/// errors: they are retried and follow `on_network_error`.
+pub trait HttpBackend: Send + Sync {
+    /// POSTs `body` as JSON to `url`.
+    fn post_json<'a>(
+        &'a self,
+        url: &'a str,
+        body: &'a serde_json::Value,
+    ) -> BoxFuture<'a, Result<BackendResponse, BackendError>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::{
+        error::TurnstileError, turnstile::verify_cloudflare_turnstile_full, TurnstileConfig,
+    };
+
+    /// Answers every request with a fixed reply, or fails if there is none.
+    struct FixedBackend(Option<BackendResponse>);
+
+    impl HttpBackend for FixedBackend {
+        fn post_json<'a>(
+            &'a self,
+            _url: &'a str,
+            _body: &'a serde_json::Value,
+        ) -> BoxFuture<'a, Result<BackendResponse, BackendError>> {
+            let reply = self.0.clone().ok_or_else(|| "connection refused".into());
+            async move { reply }.boxed()
+        }
+    }
+
+    fn config(reply: Option<BackendResponse>) -> TurnstileConfig {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.http_backend = Some(Arc::new(FixedBackend(reply)));
+        config
+    }
+
+    #[actix_web::test]
+    async fn test_backend_response() {
+        let mut config = config(Some(BackendResponse {
+            status: 200,
+            headers: vec![("CF-Ray".to_string(), "abc".to_string())],
+            body: r#"{"success": true, "hostname": "example.com"}"#.to_string(),
+        }));
+        config.captured_response_headers = vec!["cf-ray".to_string()];
+
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert!(outcome.success);
+        assert_eq!(outcome.captured_headers["cf-ray"], "abc");
+    }
+
+    #[actix_web::test]
+    async fn test_backend_rate_limited() {
+        let config = config(Some(BackendResponse {
+            status: 429,
+            headers: vec![("Retry-After".to_string(), "12".to_string())],
+            body: String::new(),
+        }));
+
+        match verify_cloudflare_turnstile_full("token", None, &config).await {
+            Err(TurnstileError::RateLimited { retry_after }) => {
+                assert_eq!(retry_after, Some(Duration::from_secs(12)));
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_backend_error_is_upstream() {
+        let err = verify_cloudflare_turnstile_full("token", None, &config(None))
+            .await
+            .expect_err("backend must fail");
+        assert!(matches!(err, TurnstileError::BackendError(_)));
+        assert!(err.is_upstream());
+    }
+}
@@ -0,0 +1,30 @@
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    web::{Bytes, BytesMut},
+    HttpMessage,
+};
+use futures_util::StreamExt;
+
+use crate::error::TurnstileError;
+
+/// Reads the whole request body into memory, up to `limit` bytes, and puts it
+/// back so the handler can still consume it.
+pub(crate) async fn buffer_body(
+    req: &mut ServiceRequest,
+    limit: usize,
+) -> Result<Bytes, TurnstileError> {
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Err(TurnstileError::PayloadTooLarge);
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let body = body.freeze();
+    req.set_payload(Payload::from(body.clone()));
+    Ok(body)
+}
@@ -0,0 +1,262 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use actix_web::dev::ServiceRequest;
+
+/// One way of determining the client IP sent to Cloudflare as `remoteip`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IpSource {
+    /// `ConnectionInfo::realip_remote_addr`: the `Forwarded` /
+    /// `X-Forwarded-For` header, falling back to the peer address. Only
+    /// trustworthy behind a proxy that overwrites those headers.
+    RealIp,
+    /// The address of the TCP peer, ignoring forwarding headers.
+    Peer,
+    /// A header holding a bare IP set by a trusted proxy, e.g.
+    /// `CF-Connecting-IP`.
+    Header(String),
+}
+
+/// Strategies for resolving the client IP, tried in order until one yields
+/// an IP. [`IpSource::RealIp`] alone by default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpSourceConfig {
+    pub sources: Vec<IpSource>,
+}
+
+impl Default for IpSourceConfig {
+    fn default() -> Self {
+        Self {
+            sources: vec![IpSource::RealIp],
+        }
+    }
+}
+
+/// The client IP as the middleware resolves it, reduced to a bare IP, for
+/// reuse in rate limiting or logging. Non-IP values (as seen over Unix
+/// domain sockets) count as missing.
+pub fn resolve_client_ip(req: &ServiceRequest, config: &IpSourceConfig) -> Option<String> {
+    config
+        .sources
+        .iter()
+        .find_map(|source| source.resolve(req))
+        .map(|ip| ip.to_string())
+}
+
+/// [`resolve_client_ip`] for plain [`http`] request parts. `RealIp` reads
+/// `Forwarded` / `X-Forwarded-For` the way actix does.
+pub(crate) fn resolve_from_parts(
+    headers: &http::HeaderMap,
+    peer: Option<SocketAddr>,
+    config: &IpSourceConfig,
+) -> Option<String> {
+    let header = |name: &str| headers.get(name)?.to_str().ok();
+
+    config
+        .sources
+        .iter()
+        .find_map(|source| match source {
+            IpSource::RealIp => forwarded_for(header("forwarded"), header("x-forwarded-for"))
+                .or(peer.map(|addr| addr.ip())),
+            IpSource::Peer => peer.map(|addr| addr.ip()),
+            IpSource::Header(name) => parse_ip(header(name)?.trim()),
+        })
+        .map(|ip| ip.to_string())
+}
+
+/// The first hop of `Forwarded` (its `for` parameter), else of
+/// `X-Forwarded-For`.
+fn forwarded_for(forwarded: Option<&str>, x_forwarded_for: Option<&str>) -> Option<IpAddr> {
+    let forwarded = forwarded.and_then(|value| {
+        let first = value.split(',').next()?;
+        first.split(';').find_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            name.eq_ignore_ascii_case("for")
+                .then(|| value.trim().trim_matches('"'))
+        })
+    });
+    let addr = match forwarded {
+        Some(addr) => addr,
+        None => x_forwarded_for?.split(',').next()?.trim(),
+    };
+    parse_ip(addr)
+}
+
+impl IpSource {
+    fn resolve(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        match self {
+            IpSource::RealIp => parse_ip(req.connection_info().realip_remote_addr()?),
+            IpSource::Peer => req.peer_addr().map(|addr| addr.ip()),
+            IpSource::Header(name) => parse_ip(req.headers().get(name)?.to_str().ok()?.trim()),
+        }
+    }
+}
+
+/// A bare IP, from an IP or socket address; IPv6 comes out without
+/// brackets, as Cloudflare expects it in `remoteip`. IPv6 zone ids
+/// (`fe80::1%eth0`) are dropped too: they only mean something on the host
+/// that saw the address, and Cloudflare rejects them.
+pub(crate) fn parse_ip(addr: &str) -> Option<IpAddr> {
+    addr.parse::<IpAddr>()
+        .or_else(|_| addr.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .or_else(|| parse_bracketed_ipv6(addr))
+        .or_else(|| parse_scoped_ipv6(addr))
+}
+
+/// `[2001:db8::1]`, a bracketed IPv6 address without a port.
+fn parse_bracketed_ipv6(addr: &str) -> Option<IpAddr> {
+    let ip = addr.strip_prefix('[')?.strip_suffix(']')?;
+    ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+}
+
+/// `fe80::1%eth0` or `[fe80::1%eth0]:443`, without the zone id.
+fn parse_scoped_ipv6(addr: &str) -> Option<IpAddr> {
+    let (ip, zone) = match addr.strip_prefix('[') {
+        Some(bracketed) => {
+            let (inner, port) = bracketed.split_once(']')?;
+            if !port.is_empty() {
+                port.strip_prefix(':')?.parse::<u16>().ok()?;
+            }
+            inner.split_once('%')?
+        }
+        None => addr.split_once('%')?,
+    };
+    let valid_zone = !zone.is_empty()
+        && zone
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid_zone {
+        return None;
+    }
+    ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn resolve(req: TestRequest, sources: Vec<IpSource>) -> Option<String> {
+        resolve_client_ip(&req.to_srv_request(), &IpSourceConfig { sources })
+    }
+
+    fn proxied_request() -> TestRequest {
+        TestRequest::default()
+            .peer_addr("10.0.0.1:12345".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .insert_header(("cf-connecting-ip", "2001:db8::1"))
+    }
+
+    #[test]
+    fn test_real_ip() {
+        let ip = resolve(proxied_request(), vec![IpSource::RealIp]);
+        assert_eq!(ip.as_deref(), Some("203.0.113.7"));
+
+        let req = TestRequest::default().peer_addr("10.0.0.1:12345".parse().unwrap());
+        let ip = resolve(req, vec![IpSource::RealIp]);
+        assert_eq!(ip.as_deref(), Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_peer() {
+        let ip = resolve(proxied_request(), vec![IpSource::Peer]);
+        assert_eq!(ip.as_deref(), Some("10.0.0.1"));
+
+        assert_eq!(resolve(TestRequest::default(), vec![IpSource::Peer]), None);
+    }
+
+    #[test]
+    fn test_header() {
+        let source = IpSource::Header("cf-connecting-ip".to_string());
+        let ip = resolve(proxied_request(), vec![source.clone()]);
+        assert_eq!(ip.as_deref(), Some("2001:db8::1"));
+
+        let req = TestRequest::default().insert_header(("cf-connecting-ip", "unknown"));
+        assert_eq!(resolve(req, vec![source]), None);
+    }
+
+    #[test]
+    fn test_resolve_from_parts() {
+        let peer = Some("10.0.0.1:12345".parse().unwrap());
+        let resolve = |pairs: &[(&'static str, &'static str)], source: IpSource| {
+            let headers: http::HeaderMap = pairs
+                .iter()
+                .map(|(name, value)| (http::HeaderName::from_static(name), value.parse().unwrap()))
+                .collect();
+            let config = IpSourceConfig {
+                sources: vec![source],
+            };
+            resolve_from_parts(&headers, peer, &config)
+        };
+
+        let forwarded = [(
+            "forwarded",
+            r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.2"#,
+        )];
+        assert_eq!(
+            resolve(&forwarded, IpSource::RealIp).as_deref(),
+            Some("2001:db8::1")
+        );
+        let x_forwarded_for = [("x-forwarded-for", "203.0.113.7, 10.0.0.2")];
+        assert_eq!(
+            resolve(&x_forwarded_for, IpSource::RealIp).as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(resolve(&[], IpSource::RealIp).as_deref(), Some("10.0.0.1"));
+        assert_eq!(
+            resolve(&x_forwarded_for, IpSource::Peer).as_deref(),
+            Some("10.0.0.1")
+        );
+        let header = IpSource::Header("cf-connecting-ip".to_string());
+        assert_eq!(
+            resolve(&[("cf-connecting-ip", "203.0.113.9")], header).as_deref(),
+            Some("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn test_ipv6_brackets_are_dropped() {
+        for addr in ["2001:db8::1", "[2001:db8::1]", "[2001:db8::1]:443"] {
+            assert_eq!(
+                parse_ip(addr),
+                Some("2001:db8::1".parse().unwrap()),
+                "{}",
+                addr
+            );
+        }
+        assert_eq!(parse_ip("[10.0.0.1]"), None);
+    }
+
+    #[test]
+    fn test_ipv6_zone_id_is_dropped() {
+        for addr in ["fe80::1%eth0", "[fe80::1%eth0]:443", "[fe80::1%25]"] {
+            assert_eq!(parse_ip(addr), Some("fe80::1".parse().unwrap()), "{}", addr);
+        }
+        for addr in [
+            "10.0.0.1%eth0",
+            "fe80::1%",
+            "fe80::1%eth0]",
+            "[fe80::1%eth0]:x",
+        ] {
+            assert_eq!(parse_ip(addr), None, "{}", addr);
+        }
+
+        let source = IpSource::Header("cf-connecting-ip".to_string());
+        let req = TestRequest::default().insert_header(("cf-connecting-ip", "fe80::1%eth0"));
+        assert_eq!(resolve(req, vec![source]).as_deref(), Some("fe80::1"));
+    }
+
+    #[test]
+    fn test_sources_are_tried_in_order() {
+        let sources = vec![
+            IpSource::Header("cf-connecting-ip".to_string()),
+            IpSource::Peer,
+        ];
+        let ip = resolve(proxied_request(), sources.clone());
+        assert_eq!(ip.as_deref(), Some("2001:db8::1"));
+
+        let req = TestRequest::default().peer_addr("10.0.0.1:12345".parse().unwrap());
+        assert_eq!(resolve(req, sources).as_deref(), Some("10.0.0.1"));
+    }
+}
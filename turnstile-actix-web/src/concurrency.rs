@@ -0,0 +1,164 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::error::TurnstileError;
+
+/// Limit on siteverify calls in flight at once, protecting the Cloudflare
+/// quota and local resources during bursts.
+///
+/// Clones share the same permits, so a single limit can span the
+/// middleware instances of every worker. Over the limit, calls fail with
+/// [`TurnstileError::TooManyVerifications`] right away, or after waiting
+/// for a permit up to [`queue_for`](Self::queue_for) while fewer than
+/// [`max_queued`](Self::max_queued) calls are waiting.
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+    max: usize,
+    queue_timeout: Option<Duration>,
+    queued: Arc<AtomicUsize>,
+    max_queued: Option<usize>,
+}
+
+impl std::fmt::Debug for ConcurrencyLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConcurrencyLimit")
+            .field("max", &self.max)
+            .field("available", &self.semaphore.available_permits())
+            .field("queue_timeout", &self.queue_timeout)
+            .field("queued", &self.queued.load(Ordering::Relaxed))
+            .field("max_queued", &self.max_queued)
+            .finish()
+    }
+}
+
+impl ConcurrencyLimit {
+    /// Allows `max` concurrent calls, rejecting any beyond.
+    pub fn new(max: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+            max,
+            queue_timeout: None,
+            queued: Arc::new(AtomicUsize::new(0)),
+            max_queued: None,
+        }
+    }
+
+    /// Lets calls over the limit wait up to `timeout` for a permit.
+    pub fn queue_for(mut self, timeout: Duration) -> Self {
+        self.queue_timeout = Some(timeout);
+        self
+    }
+
+    /// Caps the calls waiting under [`queue_for`](Self::queue_for), since
+    /// each holds its request (and any buffered body) while it waits. Calls
+    /// beyond are rejected right away. Unbounded by default.
+    pub fn max_queued(mut self, max: usize) -> Self {
+        self.max_queued = Some(max);
+        self
+    }
+
+    pub(crate) async fn acquire(&self) -> Result<SemaphorePermit<'_>, TurnstileError> {
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            return Ok(permit);
+        }
+        let (Some(timeout), Some(_slot)) = (self.queue_timeout, self.queue_slot()) else {
+            return Err(TurnstileError::TooManyVerifications);
+        };
+        actix_web::rt::time::timeout(timeout, self.semaphore.acquire())
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .ok_or(TurnstileError::TooManyVerifications)
+    }
+
+    fn queue_slot(&self) -> Option<QueueSlot<'_>> {
+        let max = self.max_queued.unwrap_or(usize::MAX);
+        self.queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                (queued < max).then_some(queued + 1)
+            })
+            .ok()?;
+        Some(QueueSlot(&self.queued))
+    }
+}
+
+/// A place in the wait queue, given up on drop.
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_rejects_over_limit() {
+        let limit = ConcurrencyLimit::new(1);
+        let permit = limit.acquire().await.unwrap();
+        assert!(matches!(
+            limit.clone().acquire().await,
+            Err(TurnstileError::TooManyVerifications)
+        ));
+
+        drop(permit);
+        assert!(limit.acquire().await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_queues_over_limit() {
+        let limit = ConcurrencyLimit::new(1).queue_for(Duration::from_millis(200));
+        let permit = limit.acquire().await.unwrap();
+        assert!(matches!(
+            limit.acquire().await,
+            Err(TurnstileError::TooManyVerifications)
+        ));
+
+        // a permit freed while waiting is handed over
+        let (_, queued) = futures_util::join!(
+            async {
+                actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+                drop(permit);
+            },
+            limit.acquire()
+        );
+        assert!(queued.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_rejects_over_queue_length() {
+        let limit = ConcurrencyLimit::new(1)
+            .queue_for(Duration::from_secs(5))
+            .max_queued(1);
+        let permit = limit.acquire().await.unwrap();
+
+        let (queued, _) = futures_util::join!(limit.acquire(), async {
+            actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+            // the queue is full, so this one doesn't wait
+            let started = std::time::Instant::now();
+            assert!(matches!(
+                limit.acquire().await,
+                Err(TurnstileError::TooManyVerifications)
+            ));
+            assert!(started.elapsed() < Duration::from_secs(1));
+            drop(permit);
+        });
+        assert!(queued.is_ok());
+        assert_eq!(limit.queued.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            actix_web::ResponseError::status_code(&TurnstileError::TooManyVerifications),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
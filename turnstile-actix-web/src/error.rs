@@ -1,6 +1,296 @@
+use std::{collections::BTreeMap, fmt, sync::Arc, time::Duration};
+
+use actix_web::{
+    body::BoxBody,
+    http::header::{HeaderName, HeaderValue, CONTENT_TYPE, LOCATION},
+    HttpResponse, ResponseError,
+};
+
+use crate::TurnstileConfig;
+
+/// Builds the response sent to the client for a rejected request.
+pub type ErrorRenderer = Arc<dyn Fn(&TurnstileError) -> HttpResponse + Send + Sync>;
+
+/// Body format of the middleware's error responses.
+#[derive(Clone, Default)]
+pub enum ErrorFormat {
+    /// `{"error": ..., "message": ..., "status": ...}` as `application/json`,
+    /// `status` being the [`ClientStatus`].
+    #[default]
+    Json,
+    /// An RFC 7807 problem document (`type`, `title`, `status`, `detail`) as
+    /// `application/problem+json`. Exposed error codes and the request id are
+    /// added as `codes` / `request_id` / `token_ref` extension members.
+    ProblemJson,
+    /// The public message as `text/plain`.
+    PlainText,
+    /// A minimal HTML page showing the public message.
+    Html,
+    /// Fully custom response. Status and headers are taken from the renderer.
+    Custom(ErrorRenderer),
+    /// `303 See Other` back to `location` (e.g. the form page), with
+    /// `error=captcha_verification_failed` and the exposed error codes as
+    /// `codes` appended to its query, for server-rendered form flows.
+    Redirect {
+        location: String,
+        /// Only redirects HTML form submissions (`application/x-www-form-urlencoded`
+        /// or `multipart/form-data`); other requests get the JSON body.
+        forms_only: bool,
+    },
+    /// `303 See Other` to `challenge_path`, a page serving the widget, with
+    /// the rejected request's path and query in `return_param` plus the
+    /// `error` and `codes` of [`Redirect`](Self::Redirect). Requests to
+    /// `challenge_path` itself are let through unverified. See the README
+    /// for the round trip.
+    Challenge {
+        challenge_path: String,
+        return_param: String,
+    },
+}
+
+impl std::fmt::Debug for ErrorFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorFormat::Json => f.write_str("Json"),
+            ErrorFormat::ProblemJson => f.write_str("ProblemJson"),
+            ErrorFormat::PlainText => f.write_str("PlainText"),
+            ErrorFormat::Html => f.write_str("Html"),
+            ErrorFormat::Custom(_) => f.write_str("Custom(..)"),
+            ErrorFormat::Redirect {
+                location,
+                forms_only,
+            } => f
+                .debug_struct("Redirect")
+                .field("location", location)
+                .field("forms_only", forms_only)
+                .finish(),
+            ErrorFormat::Challenge {
+                challenge_path,
+                return_param,
+            } => f
+                .debug_struct("Challenge")
+                .field("challenge_path", challenge_path)
+                .field("return_param", return_param)
+                .finish(),
+        }
+    }
+}
+
+/// Coarse category of a rejection for frontends to switch on, stable across
+/// changes to the [`TurnstileError`] variants; see
+/// [`TurnstileError::client_status`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientStatus {
+    /// The token expired or was already used; refresh the widget.
+    Expired,
+    /// The token was rejected.
+    Failed,
+    /// Verification isn't possible right now; retry later.
+    Unavailable,
+    /// The request carried no usable token.
+    Malformed,
+}
+
+impl ClientStatus {
+    /// The name used in JSON error bodies, e.g. `"expired"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ClientStatus::Expired => "expired",
+            ClientStatus::Failed => "failed",
+            ClientStatus::Unavailable => "unavailable",
+            ClientStatus::Malformed => "malformed",
+        }
+    }
+}
+
+/// Key names used in the JSON error body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsonErrorKeys {
+    /// Key of the stable error identifier. `"error"` by default.
+    pub error: String,
+    /// Key of the human-readable message. `"message"` by default.
+    pub message: String,
+}
+
+impl Default for JsonErrorKeys {
+    fn default() -> Self {
+        Self {
+            error: "error".to_string(),
+            message: "message".to_string(),
+        }
+    }
+}
+
+/// A JSON error body. Keys are kept sorted, the order `serde_json` writes
+/// them in.
+#[derive(Default)]
+pub(crate) struct JsonBody<'a>(BTreeMap<String, JsonField<'a>>);
+
+enum JsonField<'a> {
+    Str(&'a str),
+    Number(u16),
+    List(Vec<&'a str>),
+}
+
+impl<'a> JsonBody<'a> {
+    fn insert(&mut self, key: &str, value: JsonField<'a>) {
+        self.0.insert(key.to_owned(), value);
+    }
+}
+
+#[cfg(not(feature = "manual-error-json"))]
+impl fmt::Display for JsonBody<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let body: serde_json::Map<String, serde_json::Value> = self
+            .0
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    JsonField::Str(value) => serde_json::json!(value),
+                    JsonField::Number(value) => serde_json::json!(value),
+                    JsonField::List(values) => serde_json::json!(values),
+                };
+                (key.clone(), value)
+            })
+            .collect();
+        serde_json::Value::Object(body).fmt(f)
+    }
+}
+
+/// Writes the same output as `serde_json`, without it.
+#[cfg(feature = "manual-error-json")]
+impl fmt::Display for JsonBody<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("{")?;
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write_json_str(f, key)?;
+            f.write_str(":")?;
+            match value {
+                JsonField::Str(value) => write_json_str(f, value)?,
+                JsonField::Number(value) => write!(f, "{}", value)?,
+                JsonField::List(values) => {
+                    f.write_str("[")?;
+                    for (i, value) in values.iter().enumerate() {
+                        if i > 0 {
+                            f.write_str(",")?;
+                        }
+                        write_json_str(f, value)?;
+                    }
+                    f.write_str("]")?;
+                }
+            }
+        }
+        f.write_str("}")
+    }
+}
+
+/// A JSON string literal, escaped like `serde_json` does.
+#[cfg(feature = "manual-error-json")]
+fn write_json_str(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            '\u{08}' => f.write_str("\\b")?,
+            '\u{0c}' => f.write_str("\\f")?,
+            c if c < ' ' => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+/// Request details echoed in error bodies.
+#[derive(Default)]
+pub(crate) struct ResponseContext<'a> {
+    pub(crate) request_id: Option<&'a str>,
+    /// Only set when `include_token_ref` is enabled.
+    pub(crate) token_ref: Option<&'a str>,
+    /// The request was an HTML form submission.
+    pub(crate) form_submission: bool,
+    /// Path and query of the rejected request.
+    pub(crate) destination: Option<&'a str>,
+}
+
+impl ResponseContext<'_> {
+    fn add_to<'a>(&'a self, body: &mut JsonBody<'a>) {
+        if let Some(id) = self.request_id {
+            body.insert("request_id", JsonField::Str(id));
+        }
+        if let Some(token_ref) = self.token_ref {
+            body.insert("token_ref", JsonField::Str(token_ref));
+        }
+    }
+}
+
+/// Response header telling clients to re-run the widget and retry.
+pub const CHALLENGE_REQUIRED_HEADER: &str = "x-turnstile-challenge-required";
+
+/// Cloudflare error codes that describe our secret rather than the visitor's
+/// token. They are never echoed to clients.
+pub(crate) const SECRET_ERROR_CODES: &[&str] = &["missing-input-secret", "invalid-input-secret"];
+
+/// An error code from a siteverify response; see
+/// [`SiteVerifyResponse::error_code_set`](crate::turnstile::SiteVerifyResponse::error_code_set).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TurnstileErrorCode {
+    MissingInputSecret,
+    InvalidInputSecret,
+    MissingInputResponse,
+    InvalidInputResponse,
+    BadRequest,
+    TimeoutOrDuplicate,
+    InternalError,
+    /// A code this crate doesn't know, as received.
+    Other(String),
+}
+
+impl TurnstileErrorCode {
+    /// The code as Cloudflare spells it, e.g. `"timeout-or-duplicate"`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            TurnstileErrorCode::MissingInputSecret => "missing-input-secret",
+            TurnstileErrorCode::InvalidInputSecret => "invalid-input-secret",
+            TurnstileErrorCode::MissingInputResponse => "missing-input-response",
+            TurnstileErrorCode::InvalidInputResponse => "invalid-input-response",
+            TurnstileErrorCode::BadRequest => "bad-request",
+            TurnstileErrorCode::TimeoutOrDuplicate => "timeout-or-duplicate",
+            TurnstileErrorCode::InternalError => "internal-error",
+            TurnstileErrorCode::Other(code) => code,
+        }
+    }
+}
+
+impl From<&str> for TurnstileErrorCode {
+    fn from(code: &str) -> Self {
+        match code {
+            "missing-input-secret" => TurnstileErrorCode::MissingInputSecret,
+            "invalid-input-secret" => TurnstileErrorCode::InvalidInputSecret,
+            "missing-input-response" => TurnstileErrorCode::MissingInputResponse,
+            "invalid-input-response" => TurnstileErrorCode::InvalidInputResponse,
+            "bad-request" => TurnstileErrorCode::BadRequest,
+            "timeout-or-duplicate" => TurnstileErrorCode::TimeoutOrDuplicate,
+            "internal-error" => TurnstileErrorCode::InternalError,
+            other => TurnstileErrorCode::Other(other.to_owned()),
+        }
+    }
+}
+
+/// Errors raised while extracting or verifying a Turnstile token.
+///
+/// Variants wrapping an underlying error expose it through
+/// [`std::error::Error::source`], so the full cause chain (down to the
+/// reqwest / serde cause) can be walked by error reporters.
 #[derive(Debug, thiserror::Error)]
 pub enum TurnstileError {
-    #[error("Turnstile token not found in request headers")]
+    #[error("Turnstile token not found in request")]
     TokenNotFound,
 
     #[error("Invalid Turnstile token format")]
@@ -9,38 +299,761 @@ pub enum TurnstileError {
     #[error("Client IP address not found")]
     ClientIPNotFound,
 
-    #[error("Turnstile verification failed: {0}")]
-    VerificationFailed(String),
+    /// Cloudflare rejected the token; carries the returned `error-codes`.
+    #[error("Turnstile verification failed: {}", .0.join(", "))]
+    VerificationFailed(Vec<String>),
+
+    /// The token was issued for a hostname that isn't accepted here.
+    #[error("Turnstile hostname mismatch: {0:?}")]
+    HostnameMismatch(String),
+
+    /// The token was issued for a different widget action; carries the
+    /// returned `action`.
+    #[error("Turnstile action mismatch: {0:?}")]
+    ActionMismatch(String),
+
+    /// The token's `cdata` doesn't match the expected value.
+    #[error("Turnstile cdata mismatch")]
+    CdataMismatch,
+
+    /// The Enterprise interaction score is below
+    /// [`SuccessCheck::MinScore`](crate::policy::SuccessCheck::MinScore);
+    /// carries the returned score.
+    #[error("Turnstile score too low: {0}")]
+    ScoreTooLow(f64),
+
+    /// The challenge was solved longer ago than `max_challenge_age` allows, or
+    /// Cloudflare didn't report a usable `challenge_ts`.
+    #[error("Turnstile challenge is too old")]
+    ChallengeExpired,
+
+    /// Cloudflare reported the configured secret key as missing or invalid.
+    #[error("Turnstile secret key rejected by Cloudflare")]
+    InvalidSecret,
+
+    /// A Cloudflare test secret is configured while `reject_test_secrets` is
+    /// set.
+    #[error("Turnstile is configured with a test secret")]
+    TestSecret,
+
+    /// [`Turnstile::from_app_data`](crate::Turnstile::from_app_data) found no
+    /// `TurnstileConfig` in the app data.
+    #[error("No TurnstileConfig registered as app data")]
+    ConfigMissing,
+
+    /// The shared or configured reqwest client was used outside a Tokio
+    /// runtime, which reqwest requires.
+    #[error("Turnstile verification needs a Tokio runtime")]
+    RuntimeUnavailable,
+
+    /// The request body exceeded the configured `body_limit`.
+    #[error("Request body too large to inspect for Turnstile")]
+    PayloadTooLarge,
+
+    /// The request body couldn't be read.
+    #[error("Failed to read request body: {0}")]
+    PayloadError(#[from] actix_web::error::PayloadError),
 
     #[error("Network error during Turnstile verification: {0}")]
     NetworkError(#[from] reqwest::Error),
+
+    /// The configured [`HttpBackend`](crate::backend::HttpBackend) failed, or
+    /// its reply wasn't a siteverify response.
+    #[error("HTTP backend error during Turnstile verification: {0}")]
+    BackendError(#[source] crate::backend::BackendError),
+
+    /// Siteverify answered with something other than JSON, such as an HTML
+    /// maintenance page during an incident; carries the `Content-Type`.
+    #[error("Turnstile siteverify unavailable: unexpected {0:?} response")]
+    UpstreamUnavailable(String),
+
+    /// Cloudflare failed the verification with an error code listed in
+    /// [`TurnstileConfig::transient_error_codes`] (such as `internal-error`),
+    /// a problem on its side rather than a bad token; carries the returned
+    /// `error-codes`.
+    #[error("Turnstile siteverify failed transiently: {}", .0.join(", "))]
+    TransientFailure(Vec<String>),
+
+    /// Siteverify answered with a body that isn't JSON, or JSON that isn't a
+    /// siteverify response, e.g. without a boolean `success`.
+    #[error("Malformed Turnstile siteverify response: {0}")]
+    MalformedResponse(#[source] serde_json::Error),
+
+    #[error("Turnstile siteverify rate limit exceeded")]
+    RateLimited { retry_after: Option<Duration> },
+
+    /// The deadline from `deadline_header` passed before Cloudflare answered.
+    #[error("Turnstile verification missed the request deadline")]
+    DeadlineExceeded,
+
+    /// `max_concurrent_verifications` was reached; answered with `503`. Not
+    /// an upstream error: `on_network_error` doesn't apply, so a burst can't
+    /// fail open.
+    #[error("Too many concurrent Turnstile verifications")]
+    TooManyVerifications,
 }
 
-impl actix_web::ResponseError for TurnstileError {
-    fn status_code(&self) -> actix_web::http::StatusCode {
+/// A [`TurnstileConfig`] mistake found by
+/// [`TurnstileConfig::check`] without contacting Cloudflare.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ConfigError {
+    /// `secret_key` (or a fallback secret) is empty.
+    #[error("Turnstile secret key is empty")]
+    EmptySecret,
+    /// `verify_url` isn't an absolute `http` or `https` URL.
+    #[error("Turnstile verify_url is not an http(s) URL: {0:?}")]
+    InvalidVerifyUrl(String),
+    /// `timeout_secs` or `connect_timeout_secs` is zero, which would fail
+    /// every verification.
+    #[error("Turnstile timeout must be greater than zero")]
+    ZeroTimeout,
+    /// `connect_timeout_secs` is set along with `client` or
+    /// `rotating_client`, whose own connect timeout applies instead.
+    #[error("Turnstile connect_timeout_secs can't apply to a provided client")]
+    ConnectTimeoutWithClient,
+    /// `token_sources` is empty, so no token could ever be found.
+    #[error("Turnstile has no token sources")]
+    NoTokenSources,
+    /// A token source is listed more than once.
+    #[error("Turnstile token source listed twice: {0}")]
+    DuplicateTokenSource(String),
+    /// A Cloudflare test secret is configured while `reject_test_secrets` is
+    /// set.
+    #[error("Turnstile is configured with a test secret")]
+    TestSecret,
+}
+
+impl TurnstileError {
+    /// Whether the error originates from Cloudflare being unreachable or
+    /// refusing service, rather than from the token itself.
+    pub fn is_upstream(&self) -> bool {
+        matches!(
+            self,
+            TurnstileError::NetworkError(_)
+                | TurnstileError::BackendError(_)
+                | TurnstileError::UpstreamUnavailable(_)
+                | TurnstileError::TransientFailure(_)
+                | TurnstileError::MalformedResponse(_)
+                | TurnstileError::RateLimited { .. }
+                | TurnstileError::DeadlineExceeded
+        )
+    }
+
+    /// Whether the client can fix the error by solving a fresh challenge: the
+    /// token is missing, or it expired / was already used.
+    pub fn requires_new_challenge(&self) -> bool {
         match self {
-            TurnstileError::NetworkError(_) => actix_web::http::StatusCode::SERVICE_UNAVAILABLE,
-            _ => actix_web::http::StatusCode::BAD_REQUEST,
+            TurnstileError::TokenNotFound | TurnstileError::ChallengeExpired => true,
+            TurnstileError::VerificationFailed(codes) => {
+                codes.iter().any(|code| code == "timeout-or-duplicate")
+            }
+            _ => false,
+        }
+    }
+
+    /// The [`ClientStatus`] reported to the client for this error.
+    pub fn client_status(&self) -> ClientStatus {
+        match self {
+            TurnstileError::TokenNotFound
+            | TurnstileError::InvalidTokenFormat
+            | TurnstileError::ClientIPNotFound
+            | TurnstileError::PayloadTooLarge
+            | TurnstileError::PayloadError(_) => ClientStatus::Malformed,
+            TurnstileError::ChallengeExpired => ClientStatus::Expired,
+            TurnstileError::VerificationFailed(_) if self.requires_new_challenge() => {
+                ClientStatus::Expired
+            }
+            TurnstileError::VerificationFailed(_)
+            | TurnstileError::HostnameMismatch(_)
+            | TurnstileError::ActionMismatch(_)
+            | TurnstileError::CdataMismatch
+            | TurnstileError::ScoreTooLow(_) => ClientStatus::Failed,
+            TurnstileError::NetworkError(_)
+            | TurnstileError::BackendError(_)
+            | TurnstileError::UpstreamUnavailable(_)
+            | TurnstileError::TransientFailure(_)
+            | TurnstileError::MalformedResponse(_)
+            | TurnstileError::RateLimited { .. }
+            | TurnstileError::DeadlineExceeded
+            | TurnstileError::TooManyVerifications
+            | TurnstileError::InvalidSecret
+            | TurnstileError::TestSecret
+            | TurnstileError::ConfigMissing
+            | TurnstileError::RuntimeUnavailable => ClientStatus::Unavailable,
+        }
+    }
+
+    /// Renders the error the way the middleware answers a rejected request,
+    /// exposing only the error codes whitelisted in `config`. The error stays
+    /// attached to the response (see [`HttpResponse::error`]).
+    pub fn into_response(self, config: &TurnstileConfig) -> HttpResponse {
+        self.into_response_with(config, &ResponseContext::default())
+    }
+
+    /// Like [`into_response`](Self::into_response), additionally echoing the
+    /// request id (in the configured header) and `context` in JSON bodies.
+    pub(crate) fn into_response_with(
+        self,
+        config: &TurnstileConfig,
+        context: &ResponseContext<'_>,
+    ) -> HttpResponse {
+        let challenge_hint = config.challenge_required_header && self.requires_new_challenge();
+
+        let request_id = context.request_id;
+        let mut res = self.render(config, context);
+        if let (Some(header), Some(id)) = (&config.request_id_header, request_id) {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::try_from(header.as_str()),
+                HeaderValue::try_from(id),
+            ) {
+                res.headers_mut().insert(name, value);
+            }
+        }
+        if challenge_hint {
+            res.headers_mut().insert(
+                HeaderName::from_static(CHALLENGE_REQUIRED_HEADER),
+                HeaderValue::from_static("true"),
+            );
+        }
+        res
+    }
+
+    fn render(self, config: &TurnstileConfig, context: &ResponseContext<'_>) -> HttpResponse {
+        let format = match &config.error_format {
+            ErrorFormat::Redirect {
+                forms_only: true, ..
+            } if !context.form_submission => &ErrorFormat::Json,
+            format => format,
+        };
+
+        let (content_type, body) = match format {
+            ErrorFormat::Json => {
+                let mut body =
+                    self.json_body(&self.client_error_codes(config), &config.json_error_keys);
+                context.add_to(&mut body);
+                ("application/json", body.to_string())
+            }
+            ErrorFormat::ProblemJson => {
+                let status = self.status_code();
+                let mut body = JsonBody::default();
+                body.insert("type", JsonField::Str("about:blank"));
+                let title = status.canonical_reason().unwrap_or("Error");
+                body.insert("title", JsonField::Str(title));
+                body.insert("status", JsonField::Number(status.as_u16()));
+                body.insert("detail", JsonField::Str(self.public_message()));
+                let codes = self.client_error_codes(config);
+                if !codes.is_empty() {
+                    body.insert("codes", JsonField::List(codes));
+                }
+                context.add_to(&mut body);
+                ("application/problem+json", body.to_string())
+            }
+            ErrorFormat::PlainText => (
+                "text/plain; charset=utf-8",
+                self.public_message().to_owned(),
+            ),
+            ErrorFormat::Html => (
+                "text/html; charset=utf-8",
+                format!(
+                    "<!DOCTYPE html><html><head><title>CAPTCHA verification failed</title>\
+                     </head><body><p>{}</p></body></html>",
+                    self.public_message()
+                ),
+            ),
+            ErrorFormat::Custom(render) => {
+                // re-home the rendered parts on a response that carries the error
+                let (head, body) = render(&self).into_parts();
+                let mut res = HttpResponse::from_error(self);
+                *res.status_mut() = head.status();
+                *res.headers_mut() = head.headers().clone();
+                return res.set_body(body);
+            }
+            ErrorFormat::Redirect { location, .. } => {
+                return self.redirect(location, Vec::new(), config);
+            }
+            ErrorFormat::Challenge {
+                challenge_path,
+                return_param,
+            } => {
+                let back = context
+                    .destination
+                    .map(|destination| (return_param.as_str(), destination.to_owned()));
+                return self.redirect(challenge_path, back.into_iter().collect(), config);
+            }
+        };
+
+        let mut res = HttpResponse::from_error(self);
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        res.set_body(BoxBody::new(body))
+    }
+
+    /// `303 See Other` to `location`, with `query` and the error appended.
+    fn redirect(
+        self,
+        location: &str,
+        mut query: Vec<(&str, String)>,
+        config: &TurnstileConfig,
+    ) -> HttpResponse {
+        let codes = self.client_error_codes(config);
+        query.push(("error", "captcha_verification_failed".to_string()));
+        if !codes.is_empty() {
+            query.push(("codes", codes.join(",")));
+        }
+        let query = serde_urlencoded::to_string(query).unwrap_or_default();
+        let separator = if location.contains('?') { '&' } else { '?' };
+
+        let mut res = HttpResponse::from_error(self);
+        *res.status_mut() = actix_web::http::StatusCode::SEE_OTHER;
+        if let Ok(value) = HeaderValue::try_from(format!("{location}{separator}{query}")) {
+            res.headers_mut().insert(LOCATION, value);
         }
+        res.set_body(BoxBody::new(()))
     }
 
-    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        let public_message = match self {
+    /// The JSON error body as the client sees it, for non-actix integrations.
+    #[cfg(feature = "tower")]
+    pub(crate) fn client_json(&self, config: &TurnstileConfig) -> String {
+        self.json_body(&self.client_error_codes(config), &config.json_error_keys)
+            .to_string()
+    }
+
+    fn client_error_codes<'a>(&self, config: &'a TurnstileConfig) -> Vec<&'a str> {
+        let TurnstileError::VerificationFailed(codes) = self else {
+            return Vec::new();
+        };
+
+        codes
+            .iter()
+            .filter(|code| !SECRET_ERROR_CODES.contains(&code.as_str()))
+            .filter_map(|code| config.client_error_codes.get(code))
+            .map(String::as_str)
+            .collect()
+    }
+
+    pub(crate) fn public_message(&self) -> &'static str {
+        match self {
             TurnstileError::TokenNotFound | TurnstileError::InvalidTokenFormat => {
                 "CAPTCHA verification failed: invalid token"
             }
             TurnstileError::ClientIPNotFound => {
                 "CAPTCHA verification failed: client information missing"
             }
-            TurnstileError::VerificationFailed(_) => {
-                "CAPTCHA verification failed: please try again"
+            TurnstileError::PayloadTooLarge => {
+                "CAPTCHA verification failed: request body too large"
             }
-            TurnstileError::NetworkError(_) => "CAPTCHA service temporarily unavailable",
+            TurnstileError::PayloadError(_) => "CAPTCHA verification failed: invalid request body",
+            TurnstileError::VerificationFailed(_)
+            | TurnstileError::HostnameMismatch(_)
+            | TurnstileError::ActionMismatch(_)
+            | TurnstileError::CdataMismatch
+            | TurnstileError::ScoreTooLow(_)
+            | TurnstileError::ChallengeExpired => "CAPTCHA verification failed: please try again",
+            TurnstileError::NetworkError(_)
+            | TurnstileError::BackendError(_)
+            | TurnstileError::UpstreamUnavailable(_)
+            | TurnstileError::TransientFailure(_)
+            | TurnstileError::MalformedResponse(_)
+            | TurnstileError::RateLimited { .. }
+            | TurnstileError::DeadlineExceeded
+            | TurnstileError::TooManyVerifications
+            | TurnstileError::InvalidSecret
+            | TurnstileError::TestSecret
+            | TurnstileError::ConfigMissing
+            | TurnstileError::RuntimeUnavailable => "CAPTCHA service temporarily unavailable",
+        }
+    }
+
+    fn json_body<'a>(&self, codes: &[&'a str], keys: &JsonErrorKeys) -> JsonBody<'a> {
+        let mut body = JsonBody::default();
+        body.insert(&keys.error, JsonField::Str("captcha_verification_failed"));
+        body.insert(&keys.message, JsonField::Str(self.public_message()));
+        body.insert("status", JsonField::Str(self.client_status().as_str()));
+        if !codes.is_empty() {
+            body.insert("codes", JsonField::List(codes.to_vec()));
+        }
+        body
+    }
+}
+
+impl ResponseError for TurnstileError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            TurnstileError::NetworkError(_)
+            | TurnstileError::BackendError(_)
+            | TurnstileError::UpstreamUnavailable(_)
+            | TurnstileError::TransientFailure(_)
+            | TurnstileError::MalformedResponse(_)
+            | TurnstileError::RateLimited { .. }
+            | TurnstileError::DeadlineExceeded
+            | TurnstileError::TooManyVerifications => {
+                actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+            }
+            TurnstileError::PayloadTooLarge => actix_web::http::StatusCode::PAYLOAD_TOO_LARGE,
+            TurnstileError::InvalidSecret
+            | TurnstileError::TestSecret
+            | TurnstileError::ConfigMissing
+            | TurnstileError::RuntimeUnavailable => {
+                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => actix_web::http::StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        let mut res = HttpResponse::build(self.status_code());
+        if let TurnstileError::RateLimited {
+            retry_after: Some(retry_after),
+        } = self
+        {
+            res.insert_header((
+                actix_web::http::header::RETRY_AFTER,
+                retry_after.as_secs().to_string(),
+            ));
+        }
+
+        res.content_type("application/json")
+            .body(self.json_body(&[], &JsonErrorKeys::default()).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use actix_web::http::StatusCode;
+
+    use super::*;
+    use crate::{test_support::spawn_siteverify_stub, turnstile::verify_cloudflare_turnstile};
+
+    /// Every cause below `err`, outermost first.
+    fn source_chain(err: &TurnstileError) -> Vec<&(dyn std::error::Error + 'static)> {
+        std::iter::successors(err.source(), |&e| e.source()).collect()
+    }
+
+    #[actix_web::test]
+    async fn test_network_error_source_chain() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        // nothing listens on the discard port
+        config.verify_url = "http://127.0.0.1:9/siteverify".to_string();
+
+        let err = verify_cloudflare_turnstile("token", Some("192.168.1.1"), &config)
+            .await
+            .expect_err("connection must fail");
+        assert!(matches!(err, TurnstileError::NetworkError(_)));
+
+        let chain = source_chain(&err);
+        assert!(chain[0].is::<reqwest::Error>());
+        assert!(chain.len() > 1, "reqwest error should carry its own cause");
+    }
+
+    #[actix_web::test]
+    async fn test_decode_error_source_chain_reaches_serde() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(StatusCode::OK, vec![], "{not json");
+
+        let err = verify_cloudflare_turnstile("token", Some("192.168.1.1"), &config)
+            .await
+            .expect_err("decoding must fail");
+        assert!(matches!(err, TurnstileError::MalformedResponse(_)));
+        assert!(err.is_upstream());
+
+        let chain = source_chain(&err);
+        assert!(chain[0].is::<serde_json::Error>());
+        assert!(err.to_string().contains("key must be a string"));
+    }
+
+    async fn render(err: TurnstileError, config: &TurnstileConfig) -> (HttpResponse<()>, String) {
+        let (head, body) = err.into_response(config).into_parts();
+        let body = actix_web::body::to_bytes(body).await.unwrap();
+        (head, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn test_error_format_plain_text() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.error_format = ErrorFormat::PlainText;
+
+        let (head, body) = render(TurnstileError::TokenNotFound, &config).await;
+        assert_eq!(head.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            head.headers().get("content-type").unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(body, "CAPTCHA verification failed: invalid token");
+        assert!(head.error().unwrap().as_error::<TurnstileError>().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_error_format_plain_text_keeps_retry_after() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.error_format = ErrorFormat::PlainText;
+
+        let err = TurnstileError::RateLimited {
+            retry_after: Some(Duration::from_secs(7)),
+        };
+        let (head, _) = render(err, &config).await;
+        assert_eq!(head.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(head.headers().get("retry-after").unwrap(), "7");
+    }
+
+    #[actix_web::test]
+    async fn test_json_error_keys() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.json_error_keys = JsonErrorKeys {
+            error: "error_code".to_string(),
+            message: "error_message".to_string(),
         };
 
-        actix_web::HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "captcha_verification_failed",
-            "message": public_message
-        }))
+        let (_, body) = render(TurnstileError::TokenNotFound, &config).await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error_code": "captcha_verification_failed",
+                "error_message": "CAPTCHA verification failed: invalid token",
+                "status": "malformed"
+            })
+        );
+    }
+
+    #[cfg(feature = "manual-error-json")]
+    #[test]
+    fn test_manual_error_json_matches_serde_json() {
+        let keys = JsonErrorKeys {
+            error: "quote\" back\\slash".to_string(),
+            message: "ctl\n\r\t\u{08}\u{0c}\u{01}\u{1f} del\u{7f} é 😀".to_string(),
+        };
+        let codes = ["a\"b", "</script>"];
+        let body = TurnstileError::VerificationFailed(vec![]).json_body(&codes, &keys);
+
+        let expected = serde_json::json!({
+            keys.error.clone(): "captcha_verification_failed",
+            keys.message.clone(): "CAPTCHA verification failed: please try again",
+            "status": "failed",
+            "codes": codes,
+        });
+        assert_eq!(body.to_string(), expected.to_string());
+
+        let mut problem = JsonBody::default();
+        problem.insert("status", JsonField::Number(503));
+        problem.insert("codes", JsonField::List(vec![]));
+        assert_eq!(problem.to_string(), r#"{"codes":[],"status":503}"#);
+    }
+
+    #[actix_web::test]
+    async fn test_error_format_problem_json() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.error_format = ErrorFormat::ProblemJson;
+
+        let err = TurnstileError::VerificationFailed(vec!["timeout-or-duplicate".to_string()]);
+        let (head, body) = render(err, &config).await;
+        assert_eq!(head.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            head.headers().get("content-type").unwrap(),
+            "application/problem+json"
+        );
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Bad Request",
+                "status": 400,
+                "detail": "CAPTCHA verification failed: please try again",
+                "codes": ["token_expired"]
+            })
+        );
+
+        // the status member follows the actual response status
+        let (head, body) = render(TurnstileError::RateLimited { retry_after: None }, &config).await;
+        assert_eq!(head.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["status"], 503);
+        assert_eq!(body["title"], "Service Unavailable");
+    }
+
+    #[actix_web::test]
+    async fn test_error_format_html() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.error_format = ErrorFormat::Html;
+
+        let (head, body) = render(TurnstileError::ClientIPNotFound, &config).await;
+        assert_eq!(
+            head.headers().get("content-type").unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert!(body.contains("<p>CAPTCHA verification failed: client information missing</p>"));
+    }
+
+    #[actix_web::test]
+    async fn test_error_format_custom() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.error_format = ErrorFormat::Custom(Arc::new(|err| {
+            HttpResponse::Forbidden()
+                .insert_header(("x-captcha", "failed"))
+                .body(err.to_string())
+        }));
+
+        let (head, body) = render(TurnstileError::TokenNotFound, &config).await;
+        assert_eq!(head.status(), StatusCode::FORBIDDEN);
+        assert_eq!(head.headers().get("x-captcha").unwrap(), "failed");
+        assert_eq!(body, "Turnstile token not found in request");
+        assert!(matches!(
+            head.error().unwrap().as_error::<TurnstileError>(),
+            Some(TurnstileError::TokenNotFound)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_error_format_redirect() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.error_format = ErrorFormat::Redirect {
+            location: "/signup?step=2".to_string(),
+            forms_only: false,
+        };
+
+        let err = TurnstileError::VerificationFailed(vec!["timeout-or-duplicate".to_string()]);
+        let (head, body) = render(err, &config).await;
+        assert_eq!(head.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            head.headers().get("location").unwrap(),
+            "/signup?step=2&error=captcha_verification_failed&codes=token_expired"
+        );
+        assert!(body.is_empty());
+        assert!(head.error().unwrap().as_error::<TurnstileError>().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_error_format_redirect_forms_only() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.error_format = ErrorFormat::Redirect {
+            location: "/signup".to_string(),
+            forms_only: true,
+        };
+
+        let form = ResponseContext {
+            form_submission: true,
+            ..Default::default()
+        };
+        let res = TurnstileError::TokenNotFound.into_response_with(&config, &form);
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            res.headers().get("location").unwrap(),
+            "/signup?error=captcha_verification_failed"
+        );
+
+        let (head, _) = render(TurnstileError::TokenNotFound, &config).await;
+        assert_eq!(head.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            head.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn test_error_format_challenge() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.error_format = ErrorFormat::Challenge {
+            challenge_path: "/challenge".to_string(),
+            return_param: "return_to".to_string(),
+        };
+
+        let context = ResponseContext {
+            destination: Some("/account?tab=1"),
+            ..Default::default()
+        };
+        let res = TurnstileError::TokenNotFound.into_response_with(&config, &context);
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            res.headers().get("location").unwrap(),
+            "/challenge?return_to=%2Faccount%3Ftab%3D1&error=captcha_verification_failed"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_error_format_defaults_to_json() {
+        let config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+
+        let (head, body) = render(TurnstileError::TokenNotFound, &config).await;
+        assert_eq!(
+            head.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"], "captcha_verification_failed");
+    }
+
+    #[test]
+    fn test_challenge_required_header() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+
+        let res = TurnstileError::TokenNotFound.into_response(&config);
+        assert!(res.headers().get(CHALLENGE_REQUIRED_HEADER).is_none());
+
+        config.challenge_required_header = true;
+        let expired = TurnstileError::VerificationFailed(vec!["timeout-or-duplicate".to_string()]);
+        for err in [TurnstileError::TokenNotFound, expired] {
+            let res = err.into_response(&config);
+            assert_eq!(
+                res.headers().get(CHALLENGE_REQUIRED_HEADER).unwrap(),
+                "true"
+            );
+        }
+
+        let invalid = TurnstileError::VerificationFailed(vec!["invalid-input-secret".to_string()]);
+        for err in [TurnstileError::InvalidTokenFormat, invalid] {
+            let res = err.into_response(&config);
+            assert!(res.headers().get(CHALLENGE_REQUIRED_HEADER).is_none());
+        }
+    }
+
+    #[test]
+    fn test_client_status() {
+        let spent = TurnstileError::VerificationFailed(vec!["timeout-or-duplicate".to_string()]);
+        let invalid =
+            TurnstileError::VerificationFailed(vec!["invalid-input-response".to_string()]);
+        for (err, status) in [
+            (TurnstileError::TokenNotFound, ClientStatus::Malformed),
+            (TurnstileError::InvalidTokenFormat, ClientStatus::Malformed),
+            (spent, ClientStatus::Expired),
+            (TurnstileError::ChallengeExpired, ClientStatus::Expired),
+            (invalid, ClientStatus::Failed),
+            (TurnstileError::CdataMismatch, ClientStatus::Failed),
+            (
+                TurnstileError::RateLimited { retry_after: None },
+                ClientStatus::Unavailable,
+            ),
+            (TurnstileError::InvalidSecret, ClientStatus::Unavailable),
+        ] {
+            assert_eq!(err.client_status(), status, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn test_errors_without_cause_have_no_source() {
+        for err in [
+            TurnstileError::TokenNotFound,
+            TurnstileError::InvalidTokenFormat,
+            TurnstileError::ClientIPNotFound,
+            TurnstileError::VerificationFailed(vec!["invalid-input-response".to_string()]),
+            TurnstileError::HostnameMismatch("example.com".to_string()),
+            TurnstileError::ActionMismatch("login".to_string()),
+            TurnstileError::CdataMismatch,
+            TurnstileError::ScoreTooLow(0.1),
+            TurnstileError::ChallengeExpired,
+            TurnstileError::PayloadTooLarge,
+            TurnstileError::InvalidSecret,
+            TurnstileError::TestSecret,
+            TurnstileError::ConfigMissing,
+            TurnstileError::RuntimeUnavailable,
+            TurnstileError::UpstreamUnavailable("text/html".to_string()),
+            TurnstileError::TransientFailure(vec!["internal-error".to_string()]),
+            TurnstileError::RateLimited { retry_after: None },
+            TurnstileError::DeadlineExceeded,
+            TurnstileError::TooManyVerifications,
+        ] {
+            assert!(err.source().is_none(), "{:?} has a source", err);
+        }
     }
 }
@@ -0,0 +1,115 @@
+//! Live verification events over a `tokio::sync::broadcast` channel.
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    time::SystemTime,
+};
+
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+use crate::{error::TurnstileError, turnstile::SiteVerifyResponse, TurnstileConfig};
+
+/// Outcome reported by a [`VerificationEvent`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerificationResult {
+    Passed,
+    /// Carries the error message.
+    Failed(String),
+}
+
+/// One verification performed by the middleware.
+#[derive(Clone, Debug)]
+pub struct VerificationEvent {
+    /// Keyed hash of the client IP: stable for the lifetime of the process,
+    /// so repeat visitors can be told apart without exposing the address.
+    pub ip_hash: Option<u64>,
+    pub result: VerificationResult,
+    /// `hostname` reported by Cloudflare, if the token reached siteverify.
+    pub hostname: Option<String>,
+    /// `action` reported by Cloudflare, likewise.
+    pub action: Option<String>,
+    pub ts: SystemTime,
+}
+
+static IP_HASHER: Lazy<RandomState> = Lazy::new(RandomState::new);
+
+/// Sends an event for `result` if a channel is configured. Having no
+/// subscribers isn't an error.
+pub(crate) fn emit(
+    config: &TurnstileConfig,
+    client_ip: Option<&str>,
+    result: &Result<SiteVerifyResponse, TurnstileError>,
+) {
+    let Some(sender) = &config.event_sender else {
+        return;
+    };
+
+    let (result, hostname, action) = match result {
+        Ok(outcome) => (
+            VerificationResult::Passed,
+            outcome.hostname.clone(),
+            outcome.action.clone(),
+        ),
+        Err(err) => (VerificationResult::Failed(err.to_string()), None, None),
+    };
+    let _ = sender.send(VerificationEvent {
+        ip_hash: client_ip.map(|ip| IP_HASHER.hash_one(ip)),
+        result,
+        hostname,
+        action,
+        ts: SystemTime::now(),
+    });
+}
+
+/// Creates the sender end for [`TurnstileConfig::event_sender`], buffering
+/// up to `capacity` events per slow subscriber.
+pub fn channel(capacity: usize) -> broadcast::Sender<VerificationEvent> {
+    broadcast::channel(capacity).0
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+
+    use super::*;
+    use crate::{test_support, Turnstile};
+
+    #[actix_web::test]
+    async fn test_events_are_broadcast() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "hostname": "example.com", "action": "login"}"#,
+        );
+        config.event_sender = Some(channel(16));
+
+        let turnstile = Turnstile::new(config);
+        let mut events = turnstile.subscribe().unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(turnstile)
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::get()
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let passed = events.try_recv().unwrap();
+        assert_eq!(passed.result, VerificationResult::Passed);
+        assert_eq!(passed.hostname.as_deref(), Some("example.com"));
+        assert_eq!(passed.action.as_deref(), Some("login"));
+
+        let failed = events.try_recv().unwrap();
+        assert!(matches!(failed.result, VerificationResult::Failed(_)));
+        assert_eq!(failed.action, None);
+        assert_eq!(failed.ip_hash, passed.ip_hash);
+        assert!(failed.ip_hash.is_some());
+    }
+}
@@ -0,0 +1,351 @@
+//! [`TurnstileLayer`](crate::layer::TurnstileLayer) for gRPC services such as
+//! tonic's, answering rejections with a gRPC status instead of a JSON body.
+//!
+//! The token is read from request metadata, i.e. the configured
+//! [`TokenSource::Header`](crate::token_source::TokenSource::Header) names
+//! (lowercase, as gRPC requires). The peer address comes from a
+//! [`SocketAddr`](std::net::SocketAddr) or [`IpAddr`](std::net::IpAddr)
+//! request extension like for the tower layer. With the `tonic` feature it
+//! is also read from tonic's `TcpConnectInfo`, so
+//! `tonic::transport::Server::builder().layer(GrpcTurnstileLayer::new(config))`
+//! needs no glue; over TLS tonic wraps it in `TlsConnectInfo`, which only
+//! [`verify_tonic_request`] reads. Without the feature, copy the address over
+//! (e.g. with `tower::util::MapRequest`) or resolve the IP from a proxy header
+//! through `ip_source`.
+//!
+//! Rejections map to `PERMISSION_DENIED` (7), to `UNAVAILABLE` (14) when
+//! Cloudflare couldn't give an answer, and to `INTERNAL` (13) for
+//! misconfiguration, as a trailers-only response.
+//!
+//! tonic interceptors are synchronous and can't wait for Cloudflare, so
+//! verification runs either in the layer or in the handler, through
+//! [`verify_tonic_request`]. The `tonic` feature targets tonic 0.14, which
+//! needs Rust 1.88.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::{HeaderValue, Request, Response};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{error::TurnstileError, layer::verify, NetworkErrorPolicy, TurnstileConfig};
+#[cfg(feature = "tonic")]
+use crate::{parts::RequestParts, turnstile::SiteVerifyResponse};
+
+/// Wraps services in [`GrpcTurnstileService`].
+#[derive(Clone)]
+pub struct GrpcTurnstileLayer {
+    config: Arc<TurnstileConfig>,
+}
+
+impl GrpcTurnstileLayer {
+    pub fn new(config: TurnstileConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S> Layer<S> for GrpcTurnstileLayer {
+    type Service = GrpcTurnstileService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcTurnstileService {
+            inner,
+            config: Arc::clone(&self.config),
+        }
+    }
+}
+
+/// Verifies the token of every call before calling the inner service.
+#[derive(Clone)]
+pub struct GrpcTurnstileService<S> {
+    inner: S,
+    config: Arc<TurnstileConfig>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for GrpcTurnstileService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // the clone that was polled ready serves this request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let config = Arc::clone(&self.config);
+
+        let verification = verify(&req, Arc::clone(&config));
+        Box::pin(async move {
+            match verification.await {
+                Ok(outcome) => {
+                    req.extensions_mut().insert(outcome);
+                    inner.call(req).await
+                }
+                Err(err)
+                    if err.is_upstream()
+                        && config.on_network_error == NetworkErrorPolicy::FailOpen =>
+                {
+                    inner.call(req).await
+                }
+                Err(err) => Ok(rejection(&err)),
+            }
+        })
+    }
+}
+
+/// The gRPC status code for `err`.
+pub fn grpc_status(err: &TurnstileError) -> u16 {
+    match actix_web::ResponseError::status_code(err).as_u16() {
+        503 => 14,
+        500 => 13,
+        _ => 7,
+    }
+}
+
+/// Verifies the token of a tonic call from its handler, reading it from the
+/// metadata and the peer address from the connection (plain TCP or TLS).
+///
+/// Failures come back as the [`tonic::Status`] the layer would answer with,
+/// [`tonic_status`]. `on_network_error` isn't applied: a call that should
+/// fail open can go ahead on `UNAVAILABLE`.
+///
+/// ```no_run
+/// use tonic::{Request, Response, Status};
+/// use turnstile_actix_web::{grpc::verify_tonic_request, TurnstileConfig};
+///
+/// async fn sign_up(
+///     req: Request<String>,
+///     config: &TurnstileConfig,
+/// ) -> Result<Response<String>, Status> {
+///     let outcome = verify_tonic_request(&req, config).await?;
+///     Ok(Response::new(format!(
+///         "welcome {} from {:?}",
+///         req.get_ref(),
+///         outcome.hostname
+///     )))
+/// }
+/// ```
+///
+/// Or for every call, with the layer in front of all services:
+///
+/// ```no_run
+/// use tonic::{service::Routes, transport::Server};
+/// use turnstile_actix_web::{grpc::GrpcTurnstileLayer, TurnstileConfig};
+///
+/// async fn serve(routes: Routes) -> Result<(), tonic::transport::Error> {
+///     let config = TurnstileConfig::from_env().expect("TURNSTILE_SECRET_KEY is set");
+///     Server::builder()
+///         .layer(GrpcTurnstileLayer::new(config))
+///         .add_routes(routes)
+///         .serve("0.0.0.0:50051".parse().unwrap())
+///         .await
+/// }
+/// ```
+#[cfg(feature = "tonic")]
+pub async fn verify_tonic_request<T>(
+    req: &tonic::Request<T>,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, tonic::Status> {
+    RequestParts::read(req.metadata().as_ref(), None, req.remote_addr(), config)
+        .verify(config)
+        .await
+        .map_err(|err| tonic_status(&err))
+}
+
+/// `err` as the [`tonic::Status`] it maps to, carrying its public message.
+#[cfg(feature = "tonic")]
+pub fn tonic_status(err: &TurnstileError) -> tonic::Status {
+    let message = err.public_message();
+    match grpc_status(err) {
+        14 => tonic::Status::unavailable(message),
+        13 => tonic::Status::internal(message),
+        _ => tonic::Status::permission_denied(message),
+    }
+}
+
+fn rejection<B: Default>(err: &TurnstileError) -> Response<B> {
+    let mut res = Response::new(B::default());
+    let headers = res.headers_mut();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert("grpc-status", HeaderValue::from(grpc_status(err)));
+    // public messages are printable ASCII, which needs no percent-encoding
+    headers.insert(
+        "grpc-message",
+        HeaderValue::from_static(err.public_message()),
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, future::Ready, net::SocketAddr};
+
+    use futures_util::future::poll_fn;
+    use http::StatusCode;
+
+    use super::*;
+    use crate::test_support::spawn_siteverify_stub;
+
+    #[derive(Clone)]
+    struct Ok200;
+
+    impl Service<Request<String>> for Ok200 {
+        type Response = Response<String>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<String>) -> Self::Future {
+            std::future::ready(Ok(Response::new("reply".to_string())))
+        }
+    }
+
+    async fn oneshot(config: TurnstileConfig, token: Option<&str>) -> Response<String> {
+        let mut svc = GrpcTurnstileLayer::new(config).layer(Ok200);
+        let mut req = Request::builder();
+        if let Some(token) = token {
+            req = req.header("cf-turnstile-response", token);
+        }
+        let mut req = req.body(String::new()).unwrap();
+        req.extensions_mut()
+            .insert("192.168.1.1:12345".parse::<SocketAddr>().unwrap());
+
+        poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+        svc.call(req).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_grpc_layer() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(
+            actix_web::http::StatusCode::OK,
+            vec![],
+            r#"{"success": true}"#,
+        );
+
+        let res = oneshot(config.clone(), Some("valid_turnstile_token")).await;
+        assert_eq!(res.body(), "reply");
+        assert!(res.headers().get("grpc-status").is_none());
+
+        let res = oneshot(config, None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/grpc");
+        assert_eq!(res.headers()["grpc-status"], "7");
+        assert!(res.body().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_grpc_layer_unavailable() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(
+            actix_web::http::StatusCode::BAD_GATEWAY,
+            vec![("content-type", "text/html")],
+            "<html>bad gateway</html>",
+        );
+
+        let res = oneshot(config, Some("valid_turnstile_token")).await;
+        assert_eq!(res.headers()["grpc-status"], "14");
+        assert_eq!(
+            res.headers()["grpc-message"],
+            "CAPTCHA service temporarily unavailable"
+        );
+    }
+
+    #[cfg(feature = "tonic")]
+    fn tonic_connect_info() -> tonic::transport::server::TcpConnectInfo {
+        tonic::transport::server::TcpConnectInfo {
+            local_addr: None,
+            remote_addr: Some("192.168.1.1:12345".parse().unwrap()),
+        }
+    }
+
+    #[cfg(feature = "tonic")]
+    #[actix_web::test]
+    async fn test_grpc_layer_reads_tonic_connect_info() {
+        let (verify_url, requests) =
+            crate::test_support::spawn_recording_siteverify_stub(r#"{"success": true}"#);
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = verify_url;
+
+        let mut svc = GrpcTurnstileLayer::new(config).layer(Ok200);
+        let mut req = Request::builder()
+            .header("cf-turnstile-response", "valid_turnstile_token")
+            .body(String::new())
+            .unwrap();
+        req.extensions_mut().insert(tonic_connect_info());
+
+        poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+        let res = svc.call(req).await.unwrap();
+        assert_eq!(res.body(), "reply");
+        assert_eq!(requests.lock().unwrap()[0]["remoteip"], "192.168.1.1");
+    }
+
+    #[cfg(feature = "tonic")]
+    #[actix_web::test]
+    async fn test_verify_tonic_request() {
+        let request = |token: Option<&str>| {
+            let mut req = tonic::Request::new(());
+            if let Some(token) = token {
+                req.metadata_mut()
+                    .insert("cf-turnstile-response", token.parse().unwrap());
+            }
+            req.extensions_mut().insert(tonic_connect_info());
+            req
+        };
+
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(
+            actix_web::http::StatusCode::OK,
+            vec![],
+            r#"{"success": true}"#,
+        );
+        let outcome = verify_tonic_request(&request(Some("valid_turnstile_token")), &config)
+            .await
+            .unwrap();
+        assert!(outcome.success);
+
+        let status = verify_tonic_request(&request(None), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        // without a peer address the client IP is missing
+        let mut anonymous = request(Some("valid_turnstile_token"));
+        anonymous.extensions_mut().clear();
+        let status = verify_tonic_request(&anonymous, &config).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        config.verify_url = spawn_siteverify_stub(
+            actix_web::http::StatusCode::BAD_GATEWAY,
+            vec![("content-type", "text/html")],
+            "<html>bad gateway</html>",
+        );
+        let status = verify_tonic_request(&request(Some("valid_turnstile_token")), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(status.message(), "CAPTCHA service temporarily unavailable");
+    }
+}
@@ -0,0 +1,256 @@
+//! Turnstile verification as a tower [`Layer`], for stacks built on `tower`
+//! services (hyper, axum, tonic) next to actix.
+//!
+//! It verifies through [`parts`](crate::parts), sharing the verification
+//! core with the actix middleware: token cache, success policy, hostname
+//! checks and network error policy behave the same. Differences:
+//!
+//! - the token is only read from
+//!   [`TokenSource::Header`](crate::token_source::TokenSource::Header) sources;
+//! - the peer address is taken from a [`SocketAddr`] or [`IpAddr`] request
+//!   extension (as set by e.g. axum's `ConnectInfo` glue), or with the
+//!   `tonic` feature from tonic's `TcpConnectInfo`, then `ip_source` applies
+//!   as usual;
+//! - rejections are always rendered as the JSON error body, and
+//!   `hostname_validator` isn't applied.
+
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::{HeaderValue, Request, Response, StatusCode};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{
+    error::TurnstileError, parts::RequestParts, turnstile::SiteVerifyResponse, NetworkErrorPolicy,
+    TurnstileConfig,
+};
+
+/// Wraps services in [`TurnstileService`].
+#[derive(Clone)]
+pub struct TurnstileLayer {
+    config: Arc<TurnstileConfig>,
+}
+
+impl TurnstileLayer {
+    pub fn new(config: TurnstileConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S> Layer<S> for TurnstileLayer {
+    type Service = TurnstileService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TurnstileService {
+            inner,
+            config: Arc::clone(&self.config),
+        }
+    }
+}
+
+/// Verifies the token of every request before calling the inner service.
+#[derive(Clone)]
+pub struct TurnstileService<S> {
+    inner: S,
+    config: Arc<TurnstileConfig>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for TurnstileService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: From<String>,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // the clone that was polled ready serves this request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let config = Arc::clone(&self.config);
+
+        let verification = verify(&req, Arc::clone(&config));
+        Box::pin(async move {
+            match verification.await {
+                Ok(outcome) => {
+                    req.extensions_mut().insert(outcome);
+                    inner.call(req).await
+                }
+                Err(err)
+                    if err.is_upstream()
+                        && config.on_network_error == NetworkErrorPolicy::FailOpen =>
+                {
+                    inner.call(req).await
+                }
+                Err(err) => Ok(rejection(&err, &config)),
+            }
+        })
+    }
+}
+
+/// Reads what verification needs from the request up front, so the returned
+/// future doesn't borrow it (request bodies usually aren't `Sync`).
+pub(crate) fn verify<B>(
+    req: &Request<B>,
+    config: Arc<TurnstileConfig>,
+) -> impl Future<Output = Result<SiteVerifyResponse, TurnstileError>> + Send + 'static {
+    let extensions = req.extensions();
+    let peer = extensions
+        .get::<SocketAddr>()
+        .copied()
+        .or_else(|| extensions.get::<IpAddr>().map(|ip| SocketAddr::new(*ip, 0)));
+    #[cfg(feature = "tonic")]
+    let peer = peer.or_else(|| {
+        extensions
+            .get::<tonic::transport::server::TcpConnectInfo>()
+            .and_then(|info| info.remote_addr())
+    });
+    let parts = RequestParts::read(req.headers(), Some(req.uri()), peer, &config);
+    #[cfg(feature = "opentelemetry")]
+    let parent = crate::otel::incoming_context(&crate::otel::HttpHeaders(req.headers()));
+
+    async move {
+        let verification = parts.verify(&config);
+        #[cfg(feature = "opentelemetry")]
+        let verification = opentelemetry::context::FutureExt::with_context(verification, parent);
+        verification.await
+    }
+}
+
+fn rejection<B: From<String>>(err: &TurnstileError, config: &TurnstileConfig) -> Response<B> {
+    let status = actix_web::ResponseError::status_code(err).as_u16();
+    let mut res = Response::new(B::from(err.client_json(config)));
+    *res.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_REQUEST);
+    res.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, future::Ready};
+
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+    use futures_util::future::poll_fn;
+
+    use super::*;
+    use crate::test_support::spawn_siteverify_stub;
+
+    /// Inner service answering with the verified action.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<Request<String>> for Echo {
+        type Response = Response<String>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<String>) -> Self::Future {
+            let action = req
+                .extensions()
+                .get::<SiteVerifyResponse>()
+                .and_then(|outcome| outcome.action.clone())
+                .unwrap_or_default();
+            std::future::ready(Ok(Response::new(action)))
+        }
+    }
+
+    async fn oneshot(svc: &mut TurnstileService<Echo>, req: Request<String>) -> Response<String> {
+        poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+        svc.call(req).await.unwrap()
+    }
+
+    fn layered() -> TurnstileService<Echo> {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(
+            actix_web::http::StatusCode::OK,
+            vec![],
+            r#"{"success": true, "action": "login"}"#,
+        );
+        TurnstileLayer::new(config).layer(Echo)
+    }
+
+    #[actix_web::test]
+    async fn test_tower_layer() {
+        let mut svc = layered();
+        let peer: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+
+        let mut req = Request::builder()
+            .header("cf-turnstile-response", "valid_turnstile_token")
+            .body(String::new())
+            .unwrap();
+        req.extensions_mut().insert(peer);
+        let res = oneshot(&mut svc, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "login");
+
+        let mut req = Request::builder().body(String::new()).unwrap();
+        req.extensions_mut().insert(peer);
+        let res = oneshot(&mut svc, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res.body().contains("captcha_verification_failed"));
+    }
+
+    /// Runs an actix request through the tower service, converting between
+    /// the two request/response types.
+    async fn actix_shim(
+        req: HttpRequest,
+        body: String,
+        svc: web::Data<TurnstileService<Echo>>,
+    ) -> HttpResponse {
+        let mut builder = Request::builder()
+            .method(req.method().as_str())
+            .uri(req.uri().to_string());
+        for (name, value) in req.headers() {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+        let mut tower_req = builder.body(body).unwrap();
+        if let Some(peer) = req.peer_addr() {
+            tower_req.extensions_mut().insert(peer);
+        }
+
+        let res = oneshot(&mut svc.get_ref().clone(), tower_req).await;
+        let status = actix_web::http::StatusCode::from_u16(res.status().as_u16()).unwrap();
+        HttpResponse::build(status).body(res.into_body())
+    }
+
+    #[actix_web::test]
+    async fn test_tower_layer_inside_actix() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(layered()))
+                .default_service(web::to(actix_shim)),
+        )
+        .await;
+
+        let req = crate::test_support::token_request("valid_turnstile_token").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "login");
+
+        let req = test::TestRequest::get()
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+}
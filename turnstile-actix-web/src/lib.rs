@@ -1,22 +1,479 @@
-use std::future::{ready, Ready};
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    rc::Rc,
+    sync::{Arc, PoisonError, RwLock},
+};
 
 use actix_web::{
-    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error,
+    body::EitherBody,
+    dev::{forward_ready, ResourceDef, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{HeaderName, HeaderValue},
+        Method,
+    },
+    web, Error, HttpMessage,
 };
 
-use error::TurnstileError;
+pub use client_ip::resolve_client_ip;
+use client_ip::IpSourceConfig;
+use error::{ConfigError, ErrorFormat, JsonErrorKeys, TurnstileError};
 use futures_util::future::LocalBoxFuture;
-use turnstile::verify_cloudflare_turnstile;
+use policy::SuccessPolicy;
+use token_cache::TokenCache;
+use token_source::TokenSource;
+use turnstile::{verify_cloudflare_turnstile_full, SiteVerifyResponse};
 
+pub mod backend;
+mod body;
+pub mod client_ip;
+pub mod concurrency;
 pub mod error;
+#[cfg(feature = "events")]
+pub mod events;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "tower")]
+pub mod layer;
+#[cfg(feature = "log")]
+mod logging;
+mod multipart;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+pub mod parts;
+pub mod policy;
 pub mod reqwest_client;
+pub mod testing;
+pub mod token_cache;
+pub mod token_source;
 pub mod turnstile;
+pub mod verified_cookie;
+
+#[cfg(test)]
+mod test_support;
+
+/// Cloudflare's siteverify endpoint, the default [`TurnstileConfig::verify_url`].
+pub const CLOUDFLARE_SITEVERIFY_URL: &str =
+    "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+/// Response header carrying the verification time in milliseconds; see
+/// [`TurnstileConfig::verify_latency_header`].
+pub const VERIFY_LATENCY_HEADER: &str = "x-turnstile-verify-ms";
+
+/// What the middleware does when Cloudflare can't give an answer
+/// (network failure, rate limiting).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NetworkErrorPolicy {
+    /// Reject the request with `503 Service Unavailable`.
+    #[default]
+    FailClosed,
+    /// Let the request through unverified.
+    FailOpen,
+}
+
+/// How a siteverify response without a boolean `success` is treated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingSuccess {
+    /// As a malformed response: [`TurnstileError::MalformedResponse`], which
+    /// `on_network_error` applies to.
+    #[default]
+    Error,
+    /// As `"success": false`, rejecting the token with the returned codes.
+    Failure,
+}
+
+/// Level each verification outcome is logged at by the `log` feature.
+#[cfg(feature = "log")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogLevels {
+    /// The token was accepted. Debug by default.
+    pub passed: log::Level,
+    /// The request carried no token. Debug by default.
+    pub token_missing: log::Level,
+    /// The token was rejected, by Cloudflare or by a local check. Debug by
+    /// default.
+    pub rejected: log::Level,
+    /// Cloudflare was unavailable, or rejected the secret. Warn by default.
+    pub unavailable: log::Level,
+}
+
+#[cfg(feature = "log")]
+impl Default for LogLevels {
+    fn default() -> Self {
+        Self {
+            passed: log::Level::Debug,
+            token_missing: log::Level::Debug,
+            rejected: log::Level::Debug,
+            unavailable: log::Level::Warn,
+        }
+    }
+}
+
+/// Randomization applied to `retry_backoff`, so requests that failed together
+/// don't all retry at the same instant.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RetryJitter {
+    /// Always wait exactly `retry_backoff`.
+    #[default]
+    None,
+    /// Wait a random duration between zero and `retry_backoff`.
+    Full,
+    /// Wait half of `retry_backoff` plus a random part of the other half.
+    Equal,
+}
+
+/// Whether verification gates the request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Requests are only forwarded once their token passed verification.
+    #[default]
+    Blocking,
+    /// **Non-blocking.** Every request is forwarded immediately while the
+    /// token is verified in the background; failures are only reported to
+    /// `audit_callback`. Meant for low-risk, latency-sensitive endpoints —
+    /// it does not stop a single unverified request. `on_network_error` has
+    /// no effect; upstream errors are reported like any other failure.
+    /// `hostname_validator` isn't applied either, as the request has moved on
+    /// to the handler; `allowed_hostnames` is used instead.
+    AuditOnly,
+    /// Like `Blocking`, but only for state-changing requests: `GET`, `HEAD`
+    /// and `OPTIONS` (including CORS preflights) are forwarded unverified, so
+    /// pages embedding the widget and read-only APIs stay reachable.
+    StateChangingOnly,
+}
+
+/// A verification failure observed in [`VerifyMode::AuditOnly`], or after
+/// the fact for a request let through by `fail_open_compensation`.
+#[derive(Debug)]
+pub struct AuditRecord {
+    pub error: TurnstileError,
+    pub client_ip: Option<String>,
+    /// Method of the audited request.
+    pub method: Method,
+    /// Path of the audited request, without the query string.
+    pub path: String,
+    /// `User-Agent` of the audited request, if present and printable.
+    pub user_agent: Option<String>,
+}
+
+/// Receives verification failures in [`VerifyMode::AuditOnly`].
+pub type AuditCallback = Arc<dyn Fn(&AuditRecord) + Send + Sync>;
+
+/// Decides whether the `hostname` returned by Cloudflare is acceptable for
+/// the given request.
+pub type HostnameValidator = Arc<dyn Fn(&str, &ServiceRequest) -> bool + Send + Sync>;
+
+/// Decides per request whether the middleware should verify the token.
+pub type RequestPredicate = Arc<dyn Fn(&ServiceRequest) -> bool + Send + Sync>;
+
+/// Computes the client IP sent to Cloudflare as `remoteip`.
+pub type RemoteIpResolver = Arc<dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync>;
+
+/// Decides from the parsed JSON request body whether the middleware should
+/// verify the token.
+pub type BodyPredicate = Arc<dyn Fn(&ServiceRequest, &serde_json::Value) -> bool + Send + Sync>;
+
+/// Whether the current request went through verification, stored in the
+/// request extensions (`web::ReqData<VerificationStatus>`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerificationStatus {
+    /// The token passed verification.
+    Verified,
+    /// The request was forwarded without a verified token; `reason` names the
+    /// rule that let it through (e.g. `should_verify`, `fail_open`).
+    Skipped { reason: String },
+}
+
+/// What the middleware decided for a request, as returned by
+/// [`Turnstile::decide`].
+#[derive(Debug)]
+pub enum VerifyDecision {
+    /// The token passed verification.
+    Allowed(Box<SiteVerifyResponse>),
+    /// A rule let the request through without verification; the reason is
+    /// the one [`VerificationStatus::Skipped`] reports.
+    Skipped(&'static str),
+    /// The request is to be rejected with this error.
+    Denied(TurnstileError),
+}
+
+impl VerificationStatus {
+    fn skipped(reason: impl Into<String>) -> Self {
+        VerificationStatus::Skipped {
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Marks a request as needing verification when `verify_only_when_marked`
+/// is set.
+///
+/// The marker must be in place before the middleware runs, which means one of:
+///
+/// - inserted into the request extensions by a middleware registered *after*
+///   `Turnstile` (so it wraps it and runs first), e.g. via `wrap_fn`;
+/// - registered as app data on a scope or resource that `Turnstile` wraps
+///   (`web::resource("/").app_data(VerificationRequired).wrap(..)`).
+///
+/// App data of routes nested below the middleware isn't visible yet when it
+/// runs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VerificationRequired;
+
+/// Marks a request as safe to compensate after the fact, for
+/// [`TurnstileConfig::fail_open_compensation`]. Put in place like
+/// [`VerificationRequired`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Idempotent;
+
+/// The token of a request that may fail open, kept for
+/// `fail_open_compensation`.
+struct PendingToken(String);
 
+/// Short, non-reversible reference to the request's token: the first 8 hex
+/// digits of its SHA-256. Users can quote it in support requests and it can
+/// be grepped in logs, without exposing the token. Stored in the request
+/// extensions once a token was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenRef(pub String);
+
+impl TokenRef {
+    pub fn of(token: &str) -> Self {
+        use sha2::{Digest, Sha256};
+
+        let digest = Sha256::digest(token.as_bytes());
+        TokenRef(digest[..4].iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+/// Correlation id of the current request, available to handlers through
+/// `web::ReqData<RequestId>` when `request_id_header` is configured.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// Configuration shared by the middleware and the verification functions.
+///
+/// The config must remain `Send + Sync` so that verification futures borrowing
+/// it are `Send`; any callback fields therefore require `Send + Sync` too.
 #[derive(Clone)]
 pub struct TurnstileConfig {
     pub secret_key: String,
     pub timeout_secs: Option<u64>,
+    /// Connect timeout of siteverify calls, when it should differ from
+    /// `timeout_secs` (which the client [`Turnstile::new`] builds follows
+    /// when `None`). reqwest sets it per client only, so it applies to that
+    /// client and to the shared clients the verify functions use without
+    /// one, but can't apply to a provided `client` or `rotating_client`:
+    /// [`check`](Self::check) rejects setting both.
+    pub connect_timeout_secs: Option<u64>,
+    /// Request header carrying the caller's deadline in Unix milliseconds
+    /// (e.g. `X-Deadline`). Verification is cut short when it passes, with
+    /// [`TurnstileError::DeadlineExceeded`], which follows `on_network_error`
+    /// like a timeout. Unparsable values are ignored.
+    pub deadline_header: Option<String>,
+    pub verify_url: String,
+    pub on_network_error: NetworkErrorPolicy,
+    /// Treatment of siteverify responses lacking `success`. Error by default.
+    pub treat_missing_success_as: MissingSuccess,
+    /// Name of the error codes field in siteverify responses, for proxies
+    /// that rename it. `error-codes` by default; `error_codes` is always
+    /// accepted too.
+    pub error_codes_field: String,
+    /// Keeps the siteverify body as received on [`SiteVerifyResponse::raw`],
+    /// e.g. for audit logs. Off by default to spare the memory.
+    pub retain_raw_response: bool,
+    /// HTTP client used for siteverify calls. When `None`, [`Turnstile::new`]
+    /// builds a dedicated one from `timeout_secs` and `customize_client`.
+    pub client: Option<reqwest::Client>,
+    /// Customizes the client [`Turnstile::new`] builds (proxy, TLS,
+    /// user agent, pool and HTTP/2 settings). It receives a builder with the default
+    /// settings applied, so it can also override the timeouts. Ignored when
+    /// `client` is set.
+    pub customize_client: Option<reqwest_client::ClientCustomizer>,
+    /// A client that can be replaced at runtime; takes precedence over
+    /// `client` and `customize_client` when set.
+    pub rotating_client: Option<reqwest_client::RotatingClient>,
+    /// Cloudflare error codes that may be echoed to the client, mapped to the
+    /// stable code exposed in the error body's `codes` array. Secret-related
+    /// codes are never exposed, even if listed here.
+    pub client_error_codes: HashMap<String, String>,
+    /// **Security-sensitive.** Accepts a failed verification whose error
+    /// codes are *all* listed here, e.g. `timeout-or-duplicate` for a grace
+    /// period during a rollout. Every listed code lets the matching tokens
+    /// through unverified — with `timeout-or-duplicate`, replayed tokens are
+    /// accepted. Overrides are logged (with the `log` feature) and flagged
+    /// in [`SiteVerifyResponse::success_overridden`]. Secret-related codes
+    /// are never honored. Empty by default.
+    pub treat_as_success_codes: Vec<String>,
+    /// Error codes that mean Cloudflare had a problem rather than the token
+    /// being bad. A failed verification reporting any of them is answered
+    /// with [`TurnstileError::TransientFailure`] (`503`, following
+    /// `on_network_error`) instead of `400`, so clients retry.
+    /// `internal-error` by default.
+    pub transient_error_codes: Vec<String>,
+    /// Rejects tokens that already passed verification without asking
+    /// Cloudflare again. Disabled when `None`.
+    pub token_cache: Option<TokenCache>,
+    /// Cluster-wide replay protection; consulted next to `token_cache`.
+    pub token_store: Option<Arc<dyn token_cache::TokenStore>>,
+    /// How long `token_store` keeps accepted tokens. 300 seconds by default,
+    /// the lifetime of a Turnstile token.
+    pub token_store_ttl: std::time::Duration,
+    /// Caps the siteverify calls in flight at once. Unlimited by default.
+    pub max_concurrent_verifications: Option<concurrency::ConcurrencyLimit>,
+    /// Hostnames the widget may be served from; compared case-insensitively
+    /// against the `hostname` returned by Cloudflare. No check when `None`.
+    pub allowed_hostnames: Option<Vec<String>>,
+    /// Per-request hostname check for setups where the valid hostnames depend
+    /// on the request (e.g. multi-tenant apps). Takes precedence over
+    /// `allowed_hostnames`.
+    pub hostname_validator: Option<HostnameValidator>,
+    /// Body format of error responses. Defaults to JSON.
+    pub error_format: ErrorFormat,
+    /// Key names of the JSON error body, for API contracts that mandate
+    /// their own (e.g. `error_code` / `error_message`).
+    pub json_error_keys: JsonErrorKeys,
+    /// Rejects tokens whose challenge was solved longer ago than this.
+    /// Requires the `chrono` or `time` feature to parse `challenge_ts`.
+    #[cfg(any(feature = "chrono", feature = "time"))]
+    pub max_challenge_age: Option<std::time::Duration>,
+    /// Rejects Turnstile Enterprise tokens scoring below this with
+    /// [`TurnstileError::ScoreTooLow`]. Responses without a score pass.
+    pub min_score: Option<f64>,
+    /// Name prefixes (e.g. `cf-ratelimit-`) of siteverify response headers to
+    /// keep on [`SiteVerifyResponse::captured_headers`]. Empty by default.
+    pub captured_response_headers: Vec<String>,
+    /// Requests for which this returns `false` are forwarded without
+    /// verification. Every request is verified when `None`.
+    pub should_verify: Option<RequestPredicate>,
+    /// Rejects requests without a resolvable client IP with
+    /// [`TurnstileError::ClientIPNotFound`]. When `false`, such requests are
+    /// verified without `remoteip` — needed when listening on a Unix domain
+    /// socket, where there is no peer IP.
+    pub require_client_ip: bool,
+    /// Where the client IP is taken from; see [`client_ip::IpSource`].
+    pub ip_source: IpSourceConfig,
+    /// Replaces `ip_source` entirely, for client IPs from app-specific
+    /// sources (e.g. a decoded JWT). A returned value that doesn't parse as
+    /// an IP counts as missing.
+    pub remoteip_resolver: Option<RemoteIpResolver>,
+    /// Checks a siteverify response must pass to be accepted. The
+    /// `allowed_hostnames`, `hostname_validator`, `max_challenge_age` and
+    /// `min_score` shorthands are applied after it.
+    pub success_policy: SuccessPolicy,
+    /// Like `should_verify`, but decides from the JSON request body (e.g. to
+    /// only protect specific GraphQL mutations). Setting it makes the
+    /// middleware buffer every request body up to `body_limit` in memory
+    /// before the handler runs; the body is handed on to the handler intact.
+    /// Bodies that aren't valid JSON are always verified, larger ones are
+    /// rejected with `413 Payload Too Large`.
+    pub should_verify_body: Option<BodyPredicate>,
+    /// Maximum number of body bytes buffered for inspection. 64 KiB by default.
+    pub body_limit: usize,
+    /// Adds `X-Turnstile-Challenge-Required: true` to responses for missing or
+    /// expired tokens, so clients can refresh the widget and retry. Off by
+    /// default.
+    pub challenge_required_header: bool,
+    /// Adds `X-Turnstile-Verify-Ms` to responses of verified requests, with
+    /// the time spent verifying the token. Off by default.
+    pub verify_latency_header: bool,
+    /// Extra attempts after a network error. 0 by default.
+    pub retries: u32,
+    /// Pause between retry attempts.
+    pub retry_backoff: std::time::Duration,
+    /// Jitter applied to `retry_backoff`. None by default.
+    pub retry_jitter: RetryJitter,
+    /// Requires the `hostname` returned by Cloudflare to match the request's
+    /// `Host` (port stripped, case-insensitive). Applied in addition to
+    /// `allowed_hostnames` / `hostname_validator`.
+    pub verify_hostname_matches_request: bool,
+    /// Header carrying the request id (e.g. `x-request-id`). When set, the
+    /// incoming value, or a generated one if absent, is stored as
+    /// [`RequestId`] in the request extensions and echoed on error responses
+    /// (header and JSON `request_id`). Off when `None`.
+    pub request_id_header: Option<String>,
+    /// Adds the [`TokenRef`] of the rejected token to JSON error bodies as
+    /// `token_ref`. Off by default.
+    pub include_token_ref: bool,
+    /// Sent as `action` in the siteverify request so Turnstile analytics can
+    /// be segmented by endpoint. Purely informational; the returned `action`
+    /// is checked through `success_policy` or `action_by_path`, not this.
+    pub report_action: Option<String>,
+    /// The `action` expected per request path, so one middleware can guard
+    /// endpoints rendering different widgets. Patterns use actix route
+    /// syntax (e.g. `/posts/{id}/comment`) and the first match wins; tokens
+    /// for another action are rejected with
+    /// [`TurnstileError::ActionMismatch`]. Paths matching no pattern aren't
+    /// checked beyond `success_policy`.
+    pub action_by_path: Vec<(ResourceDef, String)>,
+    /// Blocking by default; see [`VerifyMode::AuditOnly`].
+    pub verify_mode: VerifyMode,
+    /// Called for every failed verification in [`VerifyMode::AuditOnly`].
+    pub audit_callback: Option<AuditCallback>,
+    /// With `on_network_error` set to `FailOpen`, requests marked
+    /// [`Idempotent`] that failed open are verified again in the background
+    /// after `retry_backoff`; if Cloudflare then rejects the token, this is
+    /// called so the app can undo or flag what the handler did.
+    ///
+    /// This is eventually consistent: the handler has already run (and its
+    /// response may be sent) by the time the callback fires, possibly much
+    /// later, and not at all if Cloudflare is still unavailable or the
+    /// process exits first. Compensation must be safe to apply to a request
+    /// that is long finished.
+    pub fail_open_compensation: Option<AuditCallback>,
+    /// Where to look for the token, tried in order; the first source that
+    /// carries one is used. The `cf-turnstile-response` header by default.
+    pub token_sources: Vec<TokenSource>,
+    /// Query parameter read only when none of `token_sources` carries a
+    /// token, for flows that append it to the form's action URL during
+    /// redirects ([`TokenSource::Query`] rules apply). Off when `None`.
+    pub token_query_fallback: Option<String>,
+    /// Reads every token source and rejects requests carrying differing
+    /// tokens (e.g. header and body) with
+    /// [`TurnstileError::InvalidTokenFormat`], against token confusion
+    /// between proxies and the app. Off by default.
+    pub require_token_sources_match: bool,
+    /// Further secrets tried in order when `secret_key` doesn't accept the
+    /// token, e.g. while two keys are valid during a rotation.
+    pub fallback_secret_keys: Vec<String>,
+    /// Makes [`check`](Self::check) (and so the builder) fail with
+    /// [`ConfigError::TestSecret`], and [`validate`](Self::validate) with
+    /// [`TurnstileError::TestSecret`], when `secret_key` or a fallback is one
+    /// of Cloudflare's [test secrets](testing), which accept every dummy
+    /// token. Enable it in production configs. Off by default.
+    pub reject_test_secrets: bool,
+    /// Sends siteverify requests through a custom HTTP client instead of
+    /// reqwest. See [`backend::HttpBackend`].
+    pub http_backend: Option<Arc<dyn backend::HttpBackend>>,
+    /// Runtime kill switch, read on every request: while it holds `false`,
+    /// all requests are forwarded unverified (reported as skipped for
+    /// `disabled`). Keep a clone to flip it, e.g. from an admin endpoint.
+    /// Always enabled when `None`.
+    pub enabled: Option<Arc<std::sync::atomic::AtomicBool>>,
+    /// Only verifies requests carrying the [`VerificationRequired`] marker;
+    /// all others are forwarded. For mixed read/write routes under one path.
+    pub verify_only_when_marked: bool,
+    /// Forwards requests carrying this header without verification, e.g.
+    /// once a session was established. Only *presence* is checked; the
+    /// session itself must be validated elsewhere.
+    pub skip_if_header_present: Option<String>,
+    /// Like `skip_if_header_present`, for a cookie.
+    pub skip_if_cookie_present: Option<String>,
+    /// Sets a signed cookie on responses to verified requests and forwards
+    /// later requests carrying a valid one without verification.
+    pub verified_cookie: Option<verified_cookie::VerifiedCookie>,
+    /// Only enforces verification for requests whose `Host` (port stripped,
+    /// case-insensitive) is listed; requests to other hosts are forwarded.
+    /// Every host is enforced when `None`.
+    pub enforce_on_hosts: Option<Vec<String>>,
+    /// Publishes a [`events::VerificationEvent`] per verification, e.g. for a
+    /// live dashboard. Create it with [`events::channel`] and subscribe via
+    /// [`Turnstile::subscribe`].
+    #[cfg(feature = "events")]
+    pub event_sender: Option<tokio::sync::broadcast::Sender<events::VerificationEvent>>,
+    /// Log level per verification outcome.
+    #[cfg(feature = "log")]
+    pub log_levels: LogLevels,
 }
 
 impl TurnstileConfig {
@@ -24,176 +481,1195 @@ impl TurnstileConfig {
         Self {
             secret_key: secret_key.into(),
             timeout_secs: Some(5),
+            connect_timeout_secs: None,
+            deadline_header: None,
+            verify_url: CLOUDFLARE_SITEVERIFY_URL.to_string(),
+            on_network_error: NetworkErrorPolicy::default(),
+            treat_missing_success_as: MissingSuccess::default(),
+            error_codes_field: "error-codes".to_string(),
+            retain_raw_response: false,
+            client: None,
+            customize_client: None,
+            rotating_client: None,
+            client_error_codes: HashMap::from([(
+                "timeout-or-duplicate".to_string(),
+                "token_expired".to_string(),
+            )]),
+            treat_as_success_codes: Vec::new(),
+            transient_error_codes: vec!["internal-error".to_string()],
+            token_cache: None,
+            token_store: None,
+            token_store_ttl: std::time::Duration::from_secs(300),
+            max_concurrent_verifications: None,
+            allowed_hostnames: None,
+            hostname_validator: None,
+            error_format: ErrorFormat::default(),
+            json_error_keys: JsonErrorKeys::default(),
+            #[cfg(any(feature = "chrono", feature = "time"))]
+            max_challenge_age: None,
+            min_score: None,
+            captured_response_headers: Vec::new(),
+            should_verify: None,
+            require_client_ip: true,
+            ip_source: IpSourceConfig::default(),
+            remoteip_resolver: None,
+            success_policy: SuccessPolicy::default(),
+            should_verify_body: None,
+            body_limit: 64 * 1024,
+            challenge_required_header: false,
+            verify_latency_header: false,
+            retries: 0,
+            retry_backoff: std::time::Duration::from_millis(200),
+            retry_jitter: RetryJitter::default(),
+            verify_hostname_matches_request: false,
+            request_id_header: None,
+            include_token_ref: false,
+            report_action: None,
+            action_by_path: Vec::new(),
+            verify_mode: VerifyMode::default(),
+            audit_callback: None,
+            fail_open_compensation: None,
+            token_sources: vec![TokenSource::default()],
+            token_query_fallback: None,
+            require_token_sources_match: false,
+            fallback_secret_keys: Vec::new(),
+            reject_test_secrets: false,
+            http_backend: None,
+            enabled: None,
+            verify_only_when_marked: false,
+            skip_if_header_present: None,
+            skip_if_cookie_present: None,
+            verified_cookie: None,
+            enforce_on_hosts: None,
+            #[cfg(feature = "events")]
+            event_sender: None,
+            #[cfg(feature = "log")]
+            log_levels: LogLevels::default(),
+        }
+    }
+
+    /// Builds a config from the environment:
+    ///
+    /// - `TURNSTILE_SECRET_KEY` (required)
+    /// - `TURNSTILE_VERIFY_URL` overrides the siteverify endpoint, e.g. to
+    ///   point at a local stub in dev or CI
+    pub fn from_env() -> Result<Self, std::env::VarError> {
+        let mut config = Self::new(std::env::var("TURNSTILE_SECRET_KEY")?);
+
+        if let Ok(url) = std::env::var("TURNSTILE_VERIFY_URL") {
+            config.verify_url = url;
+        }
+
+        Ok(config)
+    }
+
+    /// A builder whose [`build`](TurnstileConfigBuilder::build) runs
+    /// [`check`](Self::check).
+    pub fn builder(secret_key: impl Into<String>) -> TurnstileConfigBuilder {
+        TurnstileConfigBuilder {
+            config: Self::new(secret_key),
+        }
+    }
+
+    /// Checks the config for mistakes that can be found locally: empty
+    /// secrets, test secrets under `reject_test_secrets`, a `verify_url`
+    /// that isn't an http(s) URL, a zero timeout and missing or duplicate
+    /// token sources. Unlike [`validate`](Self::validate) nothing is sent, so
+    /// it's cheap enough for config loading.
+    pub fn check(&self) -> Result<(), ConfigError> {
+        let mut secrets = std::iter::once(&self.secret_key).chain(&self.fallback_secret_keys);
+        if secrets.any(|secret| secret.trim().is_empty()) {
+            return Err(ConfigError::EmptySecret);
+        }
+        if self.reject_test_secrets && self.uses_test_secret() {
+            return Err(ConfigError::TestSecret);
+        }
+
+        let url = reqwest::Url::parse(&self.verify_url).ok();
+        let http =
+            url.is_some_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+        if !http {
+            return Err(ConfigError::InvalidVerifyUrl(self.verify_url.clone()));
+        }
+
+        if self.timeout_secs == Some(0) || self.connect_timeout_secs == Some(0) {
+            return Err(ConfigError::ZeroTimeout);
+        }
+        let provided_client = self.client.is_some() || self.rotating_client.is_some();
+        if self.connect_timeout_secs.is_some() && provided_client {
+            return Err(ConfigError::ConnectTimeoutWithClient);
+        }
+
+        if self.token_sources.is_empty() {
+            return Err(ConfigError::NoTokenSources);
+        }
+        for (index, source) in self.token_sources.iter().enumerate() {
+            if self.token_sources[..index].contains(source) {
+                return Err(ConfigError::DuplicateTokenSource(format!("{:?}", source)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the secret key against Cloudflare by verifying a dummy token,
+    /// e.g. in `main` before binding the server.
+    ///
+    /// Returns [`TurnstileError::InvalidSecret`] if Cloudflare rejects the
+    /// secret and `Ok(())` otherwise — the dummy token itself failing is
+    /// expected. Network errors are passed through, since they say nothing
+    /// about the secret.
+    ///
+    /// With `reject_test_secrets`, test secrets fail with
+    /// [`TurnstileError::TestSecret`] before anything is sent.
+    pub async fn validate(&self) -> Result<(), TurnstileError> {
+        if self.reject_test_secrets && self.uses_test_secret() {
+            return Err(TurnstileError::TestSecret);
+        }
+
+        let outcome =
+            turnstile::verify_cloudflare_turnstile_full(testing::DUMMY_TOKEN, None, self).await?;
+
+        let secret_rejected = outcome
+            .error_codes
+            .iter()
+            .any(|code| error::SECRET_ERROR_CODES.contains(&code.as_str()));
+        if secret_rejected {
+            Err(TurnstileError::InvalidSecret)
+        } else {
+            Ok(())
         }
     }
 }
 
-pub struct Turnstile {
+impl TurnstileConfig {
+    fn uses_test_secret(&self) -> bool {
+        std::iter::once(&self.secret_key)
+            .chain(&self.fallback_secret_keys)
+            .any(|secret| testing::is_test_secret(secret))
+    }
+}
+
+/// Builds a [`TurnstileConfig`], checking it on [`build`](Self::build); see
+/// [`TurnstileConfig::builder`]. Fields without a setter are reachable
+/// through [`configure`](Self::configure).
+#[derive(Debug)]
+pub struct TurnstileConfigBuilder {
     config: TurnstileConfig,
 }
+
+impl TurnstileConfigBuilder {
+    pub fn verify_url(mut self, url: impl Into<String>) -> Self {
+        self.config.verify_url = url.into();
+        self
+    }
+
+    pub fn timeout_secs(mut self, secs: Option<u64>) -> Self {
+        self.config.timeout_secs = secs;
+        self
+    }
+
+    pub fn connect_timeout_secs(mut self, secs: Option<u64>) -> Self {
+        self.config.connect_timeout_secs = secs;
+        self
+    }
+
+    pub fn token_sources(mut self, sources: Vec<TokenSource>) -> Self {
+        self.config.token_sources = sources;
+        self
+    }
+
+    /// Sets any other field.
+    pub fn configure(mut self, configure: impl FnOnce(&mut TurnstileConfig)) -> Self {
+        configure(&mut self.config);
+        self
+    }
+
+    /// The config, if it passes [`TurnstileConfig::check`].
+    pub fn build(self) -> Result<TurnstileConfig, ConfigError> {
+        self.config.check()?;
+        Ok(self.config)
+    }
+}
+
+/// Redacts the secrets, so configs can be logged safely.
+impl std::fmt::Debug for TurnstileConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const REDACTED: &str = "***";
+        let callback = |set: bool| if set { Some("..") } else { None };
+
+        let mut debug = f.debug_struct("TurnstileConfig");
+        debug
+            .field("secret_key", &REDACTED)
+            .field(
+                "fallback_secret_keys",
+                &vec![REDACTED; self.fallback_secret_keys.len()],
+            )
+            .field("reject_test_secrets", &self.reject_test_secrets)
+            .field("timeout_secs", &self.timeout_secs)
+            .field("connect_timeout_secs", &self.connect_timeout_secs)
+            .field("deadline_header", &self.deadline_header)
+            .field("verify_url", &self.verify_url)
+            .field("on_network_error", &self.on_network_error)
+            .field("treat_missing_success_as", &self.treat_missing_success_as)
+            .field("error_codes_field", &self.error_codes_field)
+            .field("retain_raw_response", &self.retain_raw_response)
+            .field("client", &self.client)
+            .field(
+                "customize_client",
+                &callback(self.customize_client.is_some()),
+            )
+            .field("rotating_client", &self.rotating_client)
+            .field("client_error_codes", &self.client_error_codes)
+            .field("treat_as_success_codes", &self.treat_as_success_codes)
+            .field("transient_error_codes", &self.transient_error_codes)
+            .field("token_cache", &self.token_cache)
+            .field("token_store", &callback(self.token_store.is_some()))
+            .field("token_store_ttl", &self.token_store_ttl)
+            .field(
+                "max_concurrent_verifications",
+                &self.max_concurrent_verifications,
+            )
+            .field("allowed_hostnames", &self.allowed_hostnames)
+            .field(
+                "hostname_validator",
+                &callback(self.hostname_validator.is_some()),
+            )
+            .field("error_format", &self.error_format)
+            .field("json_error_keys", &self.json_error_keys);
+        #[cfg(any(feature = "chrono", feature = "time"))]
+        debug.field("max_challenge_age", &self.max_challenge_age);
+        debug
+            .field("min_score", &self.min_score)
+            .field("captured_response_headers", &self.captured_response_headers)
+            .field("should_verify", &callback(self.should_verify.is_some()))
+            .field("require_client_ip", &self.require_client_ip)
+            .field("ip_source", &self.ip_source)
+            .field(
+                "remoteip_resolver",
+                &callback(self.remoteip_resolver.is_some()),
+            )
+            .field("success_policy", &self.success_policy)
+            .field(
+                "should_verify_body",
+                &callback(self.should_verify_body.is_some()),
+            )
+            .field("body_limit", &self.body_limit)
+            .field("challenge_required_header", &self.challenge_required_header)
+            .field("verify_latency_header", &self.verify_latency_header)
+            .field("retries", &self.retries)
+            .field("retry_backoff", &self.retry_backoff)
+            .field("retry_jitter", &self.retry_jitter)
+            .field(
+                "verify_hostname_matches_request",
+                &self.verify_hostname_matches_request,
+            )
+            .field("request_id_header", &self.request_id_header)
+            .field("include_token_ref", &self.include_token_ref)
+            .field("report_action", &self.report_action)
+            .field(
+                "action_by_path",
+                &self
+                    .action_by_path
+                    .iter()
+                    .map(|(path, action)| (path.pattern().unwrap_or_default(), action))
+                    .collect::<Vec<_>>(),
+            )
+            .field("verify_mode", &self.verify_mode)
+            .field("audit_callback", &callback(self.audit_callback.is_some()))
+            .field(
+                "fail_open_compensation",
+                &callback(self.fail_open_compensation.is_some()),
+            )
+            .field("token_sources", &self.token_sources)
+            .field("token_query_fallback", &self.token_query_fallback)
+            .field(
+                "require_token_sources_match",
+                &self.require_token_sources_match,
+            )
+            .field("http_backend", &callback(self.http_backend.is_some()))
+            .field(
+                "enabled",
+                &self
+                    .enabled
+                    .as_ref()
+                    .map(|enabled| enabled.load(std::sync::atomic::Ordering::Relaxed)),
+            )
+            .field("verify_only_when_marked", &self.verify_only_when_marked)
+            .field("skip_if_header_present", &self.skip_if_header_present)
+            .field("skip_if_cookie_present", &self.skip_if_cookie_present)
+            .field("verified_cookie", &self.verified_cookie)
+            .field("enforce_on_hosts", &self.enforce_on_hosts);
+        #[cfg(feature = "events")]
+        debug.field("event_sender", &self.event_sender);
+        #[cfg(feature = "log")]
+        debug.field("log_levels", &self.log_levels);
+        debug.finish()
+    }
+}
+
+/// The Turnstile middleware.
+///
+/// Actix runs middleware in reverse registration order: the last `.wrap()`
+/// sees the request first. To verify after logging but before auth, register
+/// auth first, then `Turnstile`, then logging:
+///
+/// ```text
+/// App::new()
+///     .wrap(auth)
+///     .wrap(Turnstile::new(config))
+///     .wrap(Logger::default())
+/// ```
+///
+/// Every request forwarded to the inner service carries a
+/// [`VerificationStatus`] in its extensions — plus the
+/// [`SiteVerifyResponse`] once verified — so middleware and handlers
+/// registered inside can rely on it. Rejected requests never reach them.
+#[derive(Clone, Debug)]
+pub struct Turnstile {
+    config: Arc<TurnstileConfig>,
+    /// Whether `config.client` was built here rather than injected.
+    owns_client: bool,
+}
 impl Turnstile {
-    pub fn new(config: TurnstileConfig) -> Self {
-        Self { config }
+    pub fn new(mut config: TurnstileConfig) -> Self {
+        #[cfg(feature = "log")]
+        if config.uses_test_secret() {
+            log::warn!("turnstile is configured with a test secret; every dummy token passes");
+        }
+
+        let owns_client = config.client.is_none() && config.rotating_client.is_none();
+        if owns_client {
+            config.client = Some(reqwest_client::build_config_client(&config));
+        }
+        Self {
+            config: Arc::new(config),
+            owns_client,
+        }
+    }
+
+    /// Overrides `timeout_secs`, rounding up to whole seconds. The client
+    /// built by [`Turnstile::new`] is rebuilt to match, customized again.
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        let secs = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+        let config = Arc::make_mut(&mut self.config);
+        config.timeout_secs = Some(secs);
+        if self.owns_client {
+            config.client = Some(reqwest_client::build_config_client(config));
+        }
+        self
+    }
+
+    /// Overrides `retries`.
+    pub fn retries(mut self, retries: u32) -> Self {
+        Arc::make_mut(&mut self.config).retries = retries;
+        self
+    }
+
+    /// Overrides `retry_backoff`.
+    pub fn retry_backoff(mut self, backoff: std::time::Duration) -> Self {
+        Arc::make_mut(&mut self.config).retry_backoff = backoff;
+        self
+    }
+
+    /// Overrides `retry_jitter`.
+    pub fn retry_jitter(mut self, jitter: RetryJitter) -> Self {
+        Arc::make_mut(&mut self.config).retry_jitter = jitter;
+        self
+    }
+
+    /// Middleware sending siteverify calls to `verify_url` through `client`.
+    ///
+    /// `timeout_secs` is left unset so the client's own timeouts apply.
+    pub fn with_client_and_url(
+        secret_key: impl Into<String>,
+        client: reqwest::Client,
+        verify_url: impl Into<String>,
+    ) -> Self {
+        let mut config = TurnstileConfig::new(secret_key);
+        config.client = Some(client);
+        config.verify_url = verify_url.into();
+        config.timeout_secs = None;
+        Self::new(config)
+    }
+
+    /// Verifies `token` exactly like the middleware does — same client,
+    /// timeouts, endpoint, token cache and success checks — for handlers that
+    /// verify ad hoc. Checks that need the request (`hostname_validator`,
+    /// `verify_hostname_matches_request`) are skipped.
+    ///
+    /// Keep a clone of the `Turnstile` (e.g. as app data) to call this.
+    pub async fn verify_with(
+        &self,
+        token: &str,
+        remoteip: Option<&str>,
+    ) -> Result<SiteVerifyResponse, TurnstileError> {
+        verify_token(token, remoteip, RequestView::Absent, &self.config).await
+    }
+
+    /// Runs the middleware's rules on `req` — skip conditions, token
+    /// extraction, verification and `on_network_error` — without forwarding
+    /// or rejecting it, for services that act on the outcome themselves.
+    pub async fn decide(&self, req: &mut ServiceRequest) -> VerifyDecision {
+        decide(req, &self.config).await
+    }
+
+    /// A receiver for the configured event channel, if any.
+    #[cfg(feature = "events")]
+    pub fn subscribe(&self) -> Option<tokio::sync::broadcast::Receiver<events::VerificationEvent>> {
+        self.config
+            .event_sender
+            .as_ref()
+            .map(|sender| sender.subscribe())
+    }
+
+    /// Empties the token cache, if one is configured.
+    pub fn clear_token_cache(&self) {
+        if let Some(cache) = &self.config.token_cache {
+            cache.clear();
+        }
+    }
+
+    /// Number of tokens currently held by the token cache (0 if disabled).
+    pub fn token_cache_len(&self) -> usize {
+        self.config.token_cache.as_ref().map_or(0, TokenCache::len)
     }
 }
 
 impl<S, B> Transform<S, ServiceRequest> for Turnstile
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TurnstileMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TurnstileMiddleware {
+            service: Rc::new(service),
+            config: ConfigHandle::Fixed(Arc::clone(&self.config)),
+        }))
+    }
+}
+
+/// Middleware taking its [`TurnstileConfig`] from app data on every request,
+/// created by [`Turnstile::from_app_data`].
+///
+/// Looks for `Data<TurnstileConfig>` first, then `Data<RwLock<TurnstileConfig>>`;
+/// updating the latter reconfigures the middleware for subsequent requests
+/// (each request works on a snapshot, so keep the config lean). Its `client`
+/// isn't built for you; without one the shared default client is used.
+/// Requests fail with [`TurnstileError::ConfigMissing`] if neither is
+/// registered.
+#[derive(Clone, Debug, Default)]
+pub struct TurnstileFromAppData;
+
+impl Turnstile {
+    /// Middleware resolving its config from app data at request time, e.g.
+    /// for hot-reloadable config. See [`TurnstileFromAppData`].
+    pub fn from_app_data() -> TurnstileFromAppData {
+        TurnstileFromAppData
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for TurnstileFromAppData
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type InitError = ();
     type Transform = TurnstileMiddleware<S>;
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        let config = self.config.clone();
-        ready(Ok(TurnstileMiddleware { service, config }))
+        ready(Ok(TurnstileMiddleware {
+            service: Rc::new(service),
+            config: ConfigHandle::AppData,
+        }))
     }
 }
 
 pub struct TurnstileMiddleware<S> {
-    service: S,
-    config: TurnstileConfig,
+    service: Rc<S>,
+    config: ConfigHandle,
+}
+
+enum ConfigHandle {
+    Fixed(Arc<TurnstileConfig>),
+    AppData,
+}
+
+impl ConfigHandle {
+    fn resolve(&self, req: &ServiceRequest) -> Option<Arc<TurnstileConfig>> {
+        match self {
+            ConfigHandle::Fixed(config) => Some(Arc::clone(config)),
+            ConfigHandle::AppData => {
+                if let Some(config) = req.app_data::<web::Data<TurnstileConfig>>() {
+                    return Some(config.clone().into_inner());
+                }
+                let config = req.app_data::<web::Data<RwLock<TurnstileConfig>>>()?;
+                let config = config.read().unwrap_or_else(PoisonError::into_inner);
+                Some(Arc::new(config.clone()))
+            }
+        }
+    }
 }
 
 impl<S, B> Service<ServiceRequest> for TurnstileMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     forward_ready!(service);
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        let connection_info = req.connection_info().to_owned();
-        let client_ip = match connection_info.realip_remote_addr() {
-            Some(ip) => ip.to_owned(),
-            None => {
-                return Box::pin(async { Err(Error::from(TurnstileError::ClientIPNotFound)) });
-            }
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let Some(config) = self.config.resolve(&req) else {
+            return Box::pin(ready(Err(TurnstileError::ConfigMissing.into())));
         };
 
-        let headers = req.headers();
-        let cf_turnstile_response = match headers.get("cf-turnstile-response") {
-            Some(res) => match res.to_str() {
-                Ok(res) => res.to_owned(),
-                Err(_) => {
-                    return Box::pin(async {
-                        Err(Error::from(TurnstileError::InvalidTokenFormat))
-                    });
-                }
-            },
-            None => {
-                return Box::pin(async { Err(Error::from(TurnstileError::TokenNotFound)) });
+        // Cancellation-safe: nothing is spawned (outside of `AuditOnly` mode) and
+        // all state is owned by this future, so if actix drops it (e.g. the
+        // client disconnected) the in-flight siteverify request is dropped too
+        // and reqwest aborts its connection. The token cache is only touched
+        // once a result is in.
+        Box::pin(async move {
+            if let Some(header) = &config.request_id_header {
+                let id = read_request_id(&req, header).unwrap_or_else(generate_request_id);
+                req.extensions_mut().insert(RequestId(id));
             }
-        };
-        // println!("{}: {}", client_ip, cf_turnstile_response);
 
-        let fut = self.service.call(req);
-
-        let config = self.config.clone();
-
-        Box::pin(async move {
-            match verify_cloudflare_turnstile(&cf_turnstile_response, &client_ip, &config).await {
-                Ok(true) => {
-                    // success
-                    let res = fut.await?;
-                    Ok(res)
-                }
-                Ok(false) => {
-                    // cloudflare returned failure
-                    Err(Error::from(TurnstileError::VerificationFailed(
-                        "Cloudflare rejected the token".to_string(),
-                    )))
+            match decide(&mut req, &config).await {
+                VerifyDecision::Allowed(outcome) => {
+                    // readable by handlers as `web::ReqData<SiteVerifyResponse>`
+                    req.extensions_mut().insert(*outcome);
+                    req.extensions_mut().insert(VerificationStatus::Verified);
+                    let latency = req.extensions().get::<VerifyLatency>().copied();
+                    let mut res = service.call(req).await?;
+                    if let (true, Some(VerifyLatency(elapsed))) =
+                        (config.verify_latency_header, latency)
+                    {
+                        res.headers_mut().insert(
+                            HeaderName::from_static(VERIFY_LATENCY_HEADER),
+                            HeaderValue::from(elapsed.as_millis() as u64),
+                        );
+                    }
+                    if let Some(cookie) = &config.verified_cookie {
+                        // only fails for cookies that can't be header values
+                        let _ = res.response_mut().add_cookie(&cookie.issue());
+                    }
+                    Ok(res.map_into_left_body())
                 }
-                Err(err) => {
-                    // network error
-                    Err(Error::from(TurnstileError::NetworkError(err)))
+                VerifyDecision::Skipped(reason) => {
+                    req.extensions_mut()
+                        .insert(VerificationStatus::skipped(reason));
+                    Ok(service.call(req).await?.map_into_left_body())
                 }
+                VerifyDecision::Denied(err) => Ok(rejection_response(req, err, &config)),
             }
         })
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use actix_web::{http::header, test, web, App, HttpResponse};
+/// Time spent in [`verify_request`], for `verify_latency_header`.
+#[derive(Clone, Copy)]
+struct VerifyLatency(std::time::Duration);
 
-    use super::*;
+/// Applies the skip rules to `req`, then verifies its token.
+async fn decide(req: &mut ServiceRequest, config: &Arc<TurnstileConfig>) -> VerifyDecision {
+    let disabled = config
+        .enabled
+        .as_ref()
+        .is_some_and(|enabled| !enabled.load(std::sync::atomic::Ordering::Relaxed));
+    if disabled {
+        return VerifyDecision::Skipped("disabled");
+    }
+    if let ErrorFormat::Challenge { challenge_path, .. } = &config.error_format {
+        if req.path() == challenge_path {
+            return VerifyDecision::Skipped("challenge_page");
+        }
+    }
+    if config.verify_only_when_marked && !is_marked(req) {
+        return VerifyDecision::Skipped("not_marked");
+    }
+    if !is_enforced_host(req, config) {
+        return VerifyDecision::Skipped("enforce_on_hosts");
+    }
+    if config.verify_mode == VerifyMode::StateChangingOnly
+        && matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+    {
+        return VerifyDecision::Skipped("state_changing_only");
+    }
+    if has_session(req, config) {
+        return VerifyDecision::Skipped("session");
+    }
+    if let Some(cookie) = &config.verified_cookie {
+        if cookie.is_valid(req) {
+            return VerifyDecision::Skipped("verified_cookie");
+        }
+    }
+    if let Some(should_verify) = &config.should_verify {
+        if !should_verify(req) {
+            return VerifyDecision::Skipped("should_verify");
+        }
+    }
+    if let Some(should_verify_body) = &config.should_verify_body {
+        let body = match body::buffer_body(req, config.body_limit).await {
+            Ok(body) => body,
+            Err(err) => return VerifyDecision::Denied(err),
+        };
+        // bodies that aren't JSON are always verified
+        let parsed = serde_json::from_slice::<serde_json::Value>(&body).ok();
+        if parsed.is_some_and(|json| !should_verify_body(req, &json)) {
+            return VerifyDecision::Skipped("should_verify_body");
+        }
+    }
 
-    #[actix_web::test]
-    async fn test_turnstile_success() {
-        // Setting for test
-        let turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+    if config.verify_mode == VerifyMode::AuditOnly {
+        spawn_audit(req, Arc::clone(config)).await;
+        return VerifyDecision::Skipped("audit_only");
+    }
 
-        // Create mock server
-        let app =
-            test::init_service(App::new().wrap(Turnstile::new(turnstile_config)).service(
-                web::resource("/").to(|| async { HttpResponse::Ok().body("hello world") }),
-            ))
-            .await;
+    let client_ip = client_ip(req, config);
+    if client_ip.is_none() && config.require_client_ip {
+        return VerifyDecision::Denied(TurnstileError::ClientIPNotFound);
+    }
 
-        // Mock sample verification token
-        // this is supposed to be returned by a client
-        let token = "valid_turnstile_token";
+    #[cfg(feature = "log")]
+    let timer = logging::VerifyTimer::start(req.extensions().get::<RequestId>());
+    let started = std::time::Instant::now();
+    let deadline = config
+        .deadline_header
+        .as_ref()
+        .and_then(|header| parse_deadline(req.headers().get(header)?.to_str().ok()?));
+    #[cfg(feature = "opentelemetry")]
+    let parent = otel::incoming_context(&otel::ActixHeaders(req.headers()));
+    let verification = within_deadline(deadline, verify_request(req, client_ip.as_deref(), config));
+    #[cfg(feature = "opentelemetry")]
+    let verification = opentelemetry::context::FutureExt::with_context(verification, parent);
+    let result = verification.await;
+    req.extensions_mut()
+        .insert(VerifyLatency(started.elapsed()));
+    #[cfg(feature = "log")]
+    timer.finish(
+        &result,
+        req.extensions().get::<TokenRef>(),
+        &config.log_levels,
+    );
+    #[cfg(feature = "events")]
+    events::emit(config, client_ip.as_deref(), &result);
+    let pending = req.extensions_mut().remove::<PendingToken>();
 
-        // Build test request
-        let req = test::TestRequest::get()
-            .uri("/")
-            .insert_header((
-                header::HeaderName::from_static("cf-turnstile-response"),
-                token,
-            ))
-            .peer_addr("192.168.1.1:12345".parse().unwrap())
-            .to_request();
+    match result {
+        Ok(outcome) => VerifyDecision::Allowed(Box::new(outcome)),
+        // cloudflare unreachable or rate limited
+        Err(err)
+            if err.is_upstream() && config.on_network_error == NetworkErrorPolicy::FailOpen =>
+        {
+            if let (Some(PendingToken(token)), true) = (pending, is_idempotent(req)) {
+                spawn_compensation(req, token, client_ip, Arc::clone(config));
+            }
+            VerifyDecision::Skipped("fail_open")
+        }
+        Err(err) => VerifyDecision::Denied(err),
+    }
+}
 
-        // check request
-        let resp = test::call_service(&app, req).await;
+/// Extracts the token from `req` and verifies it.
+async fn verify_request(
+    req: &mut ServiceRequest,
+    client_ip: Option<&str>,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    let token = token_source::extract_token(req, config).await?;
+    req.extensions_mut().insert(TokenRef::of(&token));
+    if config.fail_open_compensation.is_some() {
+        req.extensions_mut().insert(PendingToken(token.clone()));
+    }
+    verify_token(&token, client_ip, RequestView::Full(req), config).await
+}
 
-        // check if success
-        assert!(resp.status().is_success());
+/// What the checks can see of the request a token came with.
+#[derive(Clone, Copy)]
+enum RequestView<'a> {
+    Full(&'a ServiceRequest),
+    /// The request moved on to the handler (see [`spawn_audit`]); only its
+    /// host and path are known, so `hostname_validator` isn't applied.
+    Detached {
+        host: Option<&'a str>,
+        path: Option<&'a str>,
+    },
+    /// No request at all ([`Turnstile::verify_with`]); request-dependent
+    /// checks are skipped.
+    Absent,
+}
+
+/// Verifies `token` and runs the configured checks, guarding against
+/// replays.
+async fn verify_token(
+    token: &str,
+    client_ip: Option<&str>,
+    request: RequestView<'_>,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    guard_replay(token, config, async {
+        let outcome = verify_cloudflare_turnstile_full(token, client_ip, config).await?;
+        check_outcome(&outcome, request, config)?;
+        Ok(outcome)
+    })
+    .await
+}
+
+/// Runs `verify` unless `token` was already accepted: rejected right away
+/// if it's in the token cache, otherwise claimed in the token store for the
+/// duration of the verification. Accepted tokens are added to the token
+/// cache; the claim on any other is given back.
+async fn guard_replay(
+    token: &str,
+    config: &TurnstileConfig,
+    verify: impl std::future::Future<Output = Result<SiteVerifyResponse, TurnstileError>>,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    let cached = config
+        .token_cache
+        .as_ref()
+        .is_some_and(|cache| cache.contains(token));
+    let claim = match &config.token_store {
+        Some(store) if !cached => {
+            let hash = token_cache::token_hash(token);
+            let claimed = store.check_and_record(&hash, config.token_store_ttl).await;
+            Some((store, hash, claimed))
+        }
+        _ => None,
+    };
+    if cached || matches!(claim, Some((_, _, false))) {
+        // replayed token, cloudflare would reject it as well
+        return Err(TurnstileError::VerificationFailed(vec![
+            "timeout-or-duplicate".to_string(),
+        ]));
     }
 
-    #[actix_web::test]
-    async fn test_turnstile_failure() {
-        // Setting for test
-        let turnstile_config = TurnstileConfig::new("2x0000000000000000000000000000000AA");
+    let result = verify.await;
+    match (&result, claim) {
+        (Ok(_), _) => {
+            if let Some(cache) = &config.token_cache {
+                cache.insert(token);
+            }
+        }
+        (Err(_), Some((store, hash, _))) => store.forget(&hash).await,
+        (Err(_), None) => {}
+    }
+    result
+}
 
-        // Create mock server
-        let app =
-            test::init_service(App::new().wrap(Turnstile::new(turnstile_config)).service(
-                web::resource("/").to(|| async { HttpResponse::Ok().body("hello world") }),
-            ))
-            .await;
+/// What a background verification keeps of a request that has moved on to
+/// the handler.
+struct DetachedRequest {
+    host: Option<String>,
+    method: Method,
+    path: String,
+    user_agent: Option<String>,
+}
 
-        // Mock sample verification token
-        // this is supposed to be returned by a client
-        let token = "valid_turnstile_token";
+impl DetachedRequest {
+    fn capture(req: &ServiceRequest) -> Self {
+        Self {
+            host: request_host(req).map(str::to_owned),
+            method: req.method().clone(),
+            path: req.path().to_owned(),
+            user_agent: req
+                .headers()
+                .get(actix_web::http::header::USER_AGENT)
+                .and_then(|ua| ua.to_str().ok())
+                .map(str::to_owned),
+        }
+    }
 
-        // Build test request
-        let req = test::TestRequest::get()
-            .uri("/")
-            .insert_header((
-                header::HeaderName::from_static("cf-turnstile-response"),
-                token,
-            ))
-            .peer_addr("192.168.1.1:12345".parse().unwrap())
-            .to_request();
+    fn view(&self) -> RequestView<'_> {
+        RequestView::Detached {
+            host: self.host.as_deref(),
+            path: Some(&self.path),
+        }
+    }
 
-        // リクエストの実行と検証
-        let resp = test::try_call_service(&app, req).await;
-        match resp {
-            Ok(response) => {
-                println!("{:?}", response);
-                assert!(response.status().is_client_error());
+    fn into_record(self, error: TurnstileError, client_ip: Option<String>) -> AuditRecord {
+        AuditRecord {
+            error,
+            client_ip,
+            method: self.method,
+            path: self.path,
+            user_agent: self.user_agent,
+        }
+    }
+}
+
+/// Verifies a request that failed open once more after `retry_backoff`,
+/// reporting a rejected token to `fail_open_compensation`.
+fn spawn_compensation(
+    req: &ServiceRequest,
+    token: String,
+    client_ip: Option<String>,
+    config: Arc<TurnstileConfig>,
+) {
+    let request = DetachedRequest::capture(req);
+
+    actix_web::rt::spawn(async move {
+        actix_web::rt::time::sleep(config.retry_backoff).await;
+        let result = verify_token(&token, client_ip.as_deref(), request.view(), &config).await;
+
+        // still no answer from cloudflare: nothing is known about the token
+        let Err(error) = result else { return };
+        if error.is_upstream() {
+            return;
+        }
+        if let Some(callback) = &config.fail_open_compensation {
+            callback(&request.into_record(error, client_ip));
+        }
+    });
+}
+
+/// Verifies the request in the background for [`VerifyMode::AuditOnly`],
+/// reporting failures to the audit callback.
+async fn spawn_audit(req: &mut ServiceRequest, config: Arc<TurnstileConfig>) {
+    // the request itself moves on to the handler, so take what's needed now
+    let client_ip = client_ip(req, &config);
+    let token = token_source::extract_token(req, &config).await;
+    let request = DetachedRequest::capture(req);
+
+    actix_web::rt::spawn(async move {
+        let result = match token {
+            _ if client_ip.is_none() && config.require_client_ip => {
+                Err(TurnstileError::ClientIPNotFound)
+            }
+            Ok(token) => verify_token(&token, client_ip.as_deref(), request.view(), &config).await,
+            Err(err) => Err(err),
+        };
+
+        #[cfg(feature = "events")]
+        events::emit(&config, client_ip.as_deref(), &result);
+
+        if let (Err(error), Some(callback)) = (result, &config.audit_callback) {
+            callback(&request.into_record(error, client_ip));
+        }
+    });
+}
+
+fn is_enforced_host(req: &ServiceRequest, config: &TurnstileConfig) -> bool {
+    let Some(hosts) = &config.enforce_on_hosts else {
+        return true;
+    };
+    request_host(req).is_some_and(|host| hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+}
+
+fn has_session(req: &ServiceRequest, config: &TurnstileConfig) -> bool {
+    let header = config
+        .skip_if_header_present
+        .as_ref()
+        .is_some_and(|name| req.headers().contains_key(name.as_str()));
+    let cookie = config
+        .skip_if_cookie_present
+        .as_ref()
+        .is_some_and(|name| req.cookie(name).is_some());
+    header || cookie
+}
+
+fn is_marked(req: &ServiceRequest) -> bool {
+    req.extensions().contains::<VerificationRequired>()
+        || req.app_data::<VerificationRequired>().is_some()
+}
+
+fn is_idempotent(req: &ServiceRequest) -> bool {
+    req.extensions().contains::<Idempotent>() || req.app_data::<Idempotent>().is_some()
+}
+
+/// The client IP per `remoteip_resolver`, else `ip_source`.
+fn client_ip(req: &ServiceRequest, config: &TurnstileConfig) -> Option<String> {
+    match &config.remoteip_resolver {
+        Some(resolver) => {
+            let ip = client_ip::parse_ip(resolver(req)?.trim())?;
+            Some(ip.to_string())
+        }
+        None => resolve_client_ip(req, &config.ip_source),
+    }
+}
+
+/// The incoming request id, if the header is present and printable.
+fn read_request_id(req: &ServiceRequest, header: &str) -> Option<String> {
+    let value = req.headers().get(header)?.to_str().ok()?;
+    (!value.is_empty()).then(|| value.to_owned())
+}
+
+/// A random 128-bit id in hex, for requests that didn't bring their own.
+fn generate_request_id() -> String {
+    format!("{:016x}{:016x}", random_u64(), random_u64())
+}
+
+/// A random `u64`, for request ids and retry jitter.
+pub(crate) fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, RandomState};
+
+    // every `RandomState` is seeded differently (from the OS once per thread,
+    // then incremented), which is unpredictable enough for ids and jitter but
+    // not for anything secret
+    RandomState::new().hash_one(0u8)
+}
+
+/// A deadline header value: milliseconds since the Unix epoch.
+fn parse_deadline(value: &str) -> Option<std::time::SystemTime> {
+    let millis = value.trim().parse().ok()?;
+    std::time::UNIX_EPOCH.checked_add(std::time::Duration::from_millis(millis))
+}
+
+/// Runs `verification`, giving up once `deadline` passes.
+async fn within_deadline<T>(
+    deadline: Option<std::time::SystemTime>,
+    verification: impl std::future::Future<Output = Result<T, TurnstileError>>,
+) -> Result<T, TurnstileError> {
+    let Some(deadline) = deadline else {
+        return verification.await;
+    };
+    let remaining = deadline
+        .duration_since(std::time::SystemTime::now())
+        .map_err(|_| TurnstileError::DeadlineExceeded)?;
+    actix_web::rt::time::timeout(remaining, verification)
+        .await
+        .unwrap_or(Err(TurnstileError::DeadlineExceeded))
+}
+
+/// The request's `Host` header (or the URI authority under HTTP/2) without
+/// the port.
+fn request_host(req: &ServiceRequest) -> Option<&str> {
+    let host = match req.headers().get(actix_web::http::header::HOST) {
+        Some(host) => host.to_str().ok()?,
+        None => req.uri().authority()?.as_str(),
+    };
+    host_name(host)
+}
+
+/// `host` (a `Host` value or URI authority) without the port.
+fn host_name(host: &str) -> Option<&str> {
+    if let Some(rest) = host.strip_prefix('[') {
+        // bracketed IPv6 literal, optionally followed by a port
+        return rest.split_once(']').map(|(ip, _)| ip);
+    }
+    Some(host.split_once(':').map_or(host, |(name, _)| name))
+}
+
+/// Runs the success policy, then the hostname and challenge age shorthands.
+fn check_outcome(
+    outcome: &SiteVerifyResponse,
+    request: RequestView<'_>,
+    config: &TurnstileConfig,
+) -> Result<(), TurnstileError> {
+    let transient = !outcome.success
+        && outcome
+            .error_codes
+            .iter()
+            .any(|code| config.transient_error_codes.contains(code));
+    if transient {
+        return Err(TurnstileError::TransientFailure(
+            outcome.error_codes.clone(),
+        ));
+    }
+    config.success_policy.evaluate(outcome)?;
+    check_action(outcome, request, config)?;
+    check_hostname(outcome, request, config)?;
+    #[cfg(any(feature = "chrono", feature = "time"))]
+    check_challenge_age(outcome, config)?;
+    if let Some(min) = config.min_score {
+        policy::SuccessCheck::MinScore(min).evaluate(outcome)?;
+    }
+    Ok(())
+}
+
+fn check_action(
+    outcome: &SiteVerifyResponse,
+    request: RequestView<'_>,
+    config: &TurnstileConfig,
+) -> Result<(), TurnstileError> {
+    let path = match request {
+        RequestView::Full(req) => Some(req.path()),
+        RequestView::Detached { path, .. } => path,
+        RequestView::Absent => None,
+    };
+    let Some(path) = path else {
+        return Ok(());
+    };
+    match config
+        .action_by_path
+        .iter()
+        .find(|(pattern, _)| pattern.is_match(path))
+    {
+        Some((_, action)) => policy::SuccessCheck::Action(action.clone()).evaluate(outcome),
+        None => Ok(()),
+    }
+}
+
+fn check_hostname(
+    outcome: &SiteVerifyResponse,
+    request: RequestView<'_>,
+    config: &TurnstileConfig,
+) -> Result<(), TurnstileError> {
+    match (&config.hostname_validator, request) {
+        (Some(validator), RequestView::Full(req)) => {
+            let hostname = outcome.hostname.as_deref().unwrap_or_default();
+            if !validator(hostname, req) {
+                return Err(TurnstileError::HostnameMismatch(hostname.to_owned()));
+            }
+        }
+        // without the request, fall back to the static list
+        _ => {
+            if let Some(allowed) = &config.allowed_hostnames {
+                policy::check_hostname_in(allowed, outcome)?;
+            }
+        }
+    }
+
+    let request_host = match request {
+        RequestView::Full(req) => request_host(req),
+        RequestView::Detached { host, .. } => host,
+        RequestView::Absent => return Ok(()),
+    };
+    if config.verify_hostname_matches_request {
+        let hostname = outcome.hostname.as_deref().unwrap_or_default();
+        let matches = request_host.is_some_and(|host| host.eq_ignore_ascii_case(hostname));
+        if !matches {
+            return Err(TurnstileError::HostnameMismatch(hostname.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "chrono", feature = "time"))]
+fn check_challenge_age(
+    outcome: &SiteVerifyResponse,
+    config: &TurnstileConfig,
+) -> Result<(), TurnstileError> {
+    match config.max_challenge_age {
+        Some(max_age) => policy::SuccessCheck::MaxChallengeAge(max_age).evaluate(outcome),
+        None => Ok(()),
+    }
+}
+
+/// Answers the request with the rendered error response.
+fn rejection_response<B>(
+    req: ServiceRequest,
+    err: TurnstileError,
+    config: &TurnstileConfig,
+) -> ServiceResponse<EitherBody<B>> {
+    // `ServiceRequest::into_response` would drop the error attached to the response
+    let request_id = req.extensions().get::<RequestId>().cloned();
+    let token_ref = if config.include_token_ref {
+        req.extensions().get::<TokenRef>().cloned()
+    } else {
+        None
+    };
+    let form_submission = req.mime_type().ok().flatten().is_some_and(|mime| {
+        matches!(
+            mime.essence_str(),
+            "application/x-www-form-urlencoded" | "multipart/form-data"
+        )
+    });
+    let (http_req, _) = req.into_parts();
+
+    let context = error::ResponseContext {
+        request_id: request_id.as_ref().map(|id| &*id.0),
+        token_ref: token_ref.as_ref().map(|token_ref| &*token_ref.0),
+        form_submission,
+        destination: http_req
+            .uri()
+            .path_and_query()
+            .map(|target| target.as_str()),
+    };
+    let res = err.into_response_with(config, &context);
+    ServiceResponse::new(http_req, res.map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test, web, App, HttpResponse,
+    };
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_turnstile_success() {
+        // Setting for test
+        let turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+
+        // Create mock server
+        let app =
+            test::init_service(App::new().wrap(Turnstile::new(turnstile_config)).service(
+                web::resource("/").to(|| async { HttpResponse::Ok().body("hello world") }),
+            ))
+            .await;
+
+        // Mock sample verification token
+        // this is supposed to be returned by a client
+        let token = "valid_turnstile_token";
+
+        // Build test request
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((
+                header::HeaderName::from_static("cf-turnstile-response"),
+                token,
+            ))
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+
+        // check request
+        let resp = test::call_service(&app, req).await;
+
+        // check if success
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::to_string_in_format_args)]
+    async fn test_turnstile_failure() {
+        // Setting for test
+        let turnstile_config = TurnstileConfig::new("2x0000000000000000000000000000000AA");
+
+        // Create mock server
+        let app =
+            test::init_service(App::new().wrap(Turnstile::new(turnstile_config)).service(
+                web::resource("/").to(|| async { HttpResponse::Ok().body("hello world") }),
+            ))
+            .await;
+
+        // Mock sample verification token
+        // this is supposed to be returned by a client
+        let token = "valid_turnstile_token";
+
+        // Build test request
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((
+                header::HeaderName::from_static("cf-turnstile-response"),
+                token,
+            ))
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+
+        // リクエストの実行と検証
+        let resp = test::try_call_service(&app, req).await;
+        match resp {
+            Ok(response) => {
+                println!("{:?}", response);
+                assert!(response.status().is_client_error());
             }
             Err(e) => {
                 if let Some(turnstile_error) = e.as_error::<TurnstileError>() {
@@ -211,4 +1687,2109 @@ mod tests {
             }
         }
     }
+
+    /// Extracts the `TurnstileError` a rejection response was rendered from.
+    fn rejection(resp: &ServiceResponse<impl actix_web::body::MessageBody>) -> &TurnstileError {
+        resp.response()
+            .error()
+            .and_then(|e| e.as_error::<TurnstileError>())
+            .expect("response carries no TurnstileError")
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_rate_limited_responds_service_unavailable() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::TOO_MANY_REQUESTS,
+            vec![("retry-after", "12")],
+            "",
+        );
+
+        let app =
+            test::init_service(App::new().wrap(Turnstile::new(turnstile_config)).service(
+                web::resource("/").to(|| async { HttpResponse::Ok().body("hello world") }),
+            ))
+            .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "12");
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::RateLimited { .. }
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_rate_limited_fail_open() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::TOO_MANY_REQUESTS, vec![], "");
+        turnstile_config.on_network_error = NetworkErrorPolicy::FailOpen;
+
+        let app =
+            test::init_service(App::new().wrap(Turnstile::new(turnstile_config)).service(
+                web::resource("/").to(|| async { HttpResponse::Ok().body("hello world") }),
+            ))
+            .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_per_instance_timeout() {
+        let url = test_support::spawn_delayed_siteverify_stub(
+            std::time::Duration::from_secs(2),
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true}"#,
+        );
+
+        let mut impatient = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        impatient.verify_url = url.clone();
+        impatient.timeout_secs = Some(1);
+
+        let mut patient = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        patient.verify_url = url;
+        patient.timeout_secs = Some(10);
+
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/interactive")
+                        .wrap(Turnstile::new(impatient))
+                        .route("", web::get().to(HttpResponse::Ok)),
+                )
+                .service(
+                    web::scope("/background")
+                        .wrap(Turnstile::new(patient))
+                        .route("", web::get().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token")
+            .uri("/interactive")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        match rejection(&resp) {
+            TurnstileError::NetworkError(e) => assert!(e.is_timeout()),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+
+        let req = test_support::token_request("valid_turnstile_token")
+            .uri("/background")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_connect_timeout() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_delayed_siteverify_stub(
+            std::time::Duration::from_secs(2),
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true}"#,
+        );
+        turnstile_config.timeout_secs = Some(5);
+        turnstile_config.connect_timeout_secs = Some(1);
+        assert_eq!(
+            reqwest_client::config_timeouts(&turnstile_config),
+            (
+                Some(std::time::Duration::from_secs(5)),
+                Some(std::time::Duration::from_secs(1))
+            )
+        );
+
+        // a slow answer on a quick connection only runs into the total timeout
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let mut defaults = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        defaults.timeout_secs = Some(3);
+        assert_eq!(
+            reqwest_client::config_timeouts(&defaults),
+            (
+                Some(std::time::Duration::from_secs(3)),
+                Some(std::time::Duration::from_secs(3))
+            )
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_connect_timeout_applies() {
+        use std::time::{Duration, Instant};
+
+        let stub = test_support::spawn_unconnectable_siteverify_stub();
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = stub.url.clone();
+        turnstile_config.timeout_secs = Some(5);
+        turnstile_config.connect_timeout_secs = Some(1);
+
+        let gives_up_connecting = |result: Result<bool, TurnstileError>, started: Instant| {
+            let elapsed = started.elapsed();
+            assert!(elapsed < Duration::from_secs(3), "took {:?}", elapsed);
+            match result {
+                Err(TurnstileError::NetworkError(err)) => {
+                    assert!(err.is_connect() && err.is_timeout(), "{:?}", err)
+                }
+                other => panic!("expected a connect timeout, got {:?}", other),
+            }
+        };
+
+        // the shared client of the verify functions
+        let started = Instant::now();
+        let result = turnstile::verify_cloudflare_turnstile("token", None, &turnstile_config).await;
+        gives_up_connecting(result, started);
+
+        // the client `Turnstile::new` builds
+        let turnstile = Turnstile::new(turnstile_config);
+        let started = Instant::now();
+        let result = turnstile::verify_cloudflare_turnstile("token", None, &turnstile.config).await;
+        gives_up_connecting(result, started);
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_exposes_whitelisted_error_codes() {
+        let mut turnstile_config = TurnstileConfig::new("2x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error-codes": ["timeout-or-duplicate", "invalid-input-secret"]}"#,
+        );
+        // listing a secret-related code must not leak it
+        turnstile_config
+            .client_error_codes
+            .insert("invalid-input-secret".to_string(), "bad_secret".to_string());
+
+        let app =
+            test::init_service(App::new().wrap(Turnstile::new(turnstile_config)).service(
+                web::resource("/").to(|| async { HttpResponse::Ok().body("hello world") }),
+            ))
+            .await;
+
+        let req = test_support::token_request("expired_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "captcha_verification_failed");
+        assert_eq!(body["codes"], serde_json::json!(["token_expired"]));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_hides_unlisted_error_codes() {
+        let mut turnstile_config = TurnstileConfig::new("2x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error-codes": ["invalid-input-response"]}"#,
+        );
+
+        let app =
+            test::init_service(App::new().wrap(Turnstile::new(turnstile_config)).service(
+                web::resource("/").to(|| async { HttpResponse::Ok().body("hello world") }),
+            ))
+            .await;
+
+        let req = test_support::token_request("invalid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::VerificationFailed(codes) if codes == &["invalid-input-response"]
+        ));
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(body.get("codes").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_token_cache_rejects_replays() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+        turnstile_config.token_cache = Some(TokenCache::new(16));
+
+        let turnstile = Turnstile::new(turnstile_config);
+        let handle = Turnstile::new((*turnstile.config).clone());
+
+        let app = test::init_service(
+            App::new()
+                .wrap(turnstile)
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("single_use_token").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(handle.token_cache_len(), 1);
+
+        let req = test_support::token_request("single_use_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::VerificationFailed(codes) if codes == &["timeout-or-duplicate"]
+        ));
+
+        handle.clear_token_cache();
+        assert_eq!(handle.token_cache_len(), 0);
+
+        let req = test_support::token_request("single_use_token").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_hostname_validator_per_tenant() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "hostname": "tenant-a.example.com"}"#,
+        );
+        // the validator wins over the static list
+        turnstile_config.allowed_hostnames = Some(vec!["www.example.com".to_string()]);
+        turnstile_config.hostname_validator = Some(Arc::new(|hostname, req| {
+            let tenant = req.headers().get("x-tenant").and_then(|v| v.to_str().ok());
+            tenant == hostname.split('.').next()
+        }));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token")
+            .insert_header(("x-tenant", "tenant-a"))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test_support::token_request("valid_turnstile_token")
+            .insert_header(("x-tenant", "tenant-b"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::HostnameMismatch(hostname) if hostname == "tenant-a.example.com"
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_allowed_hostnames() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "hostname": "WWW.example.com"}"#,
+        );
+        turnstile_config.allowed_hostnames = Some(vec!["www.example.com".to_string()]);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    #[cfg(any(feature = "chrono", feature = "time"))]
+    #[actix_web::test]
+    async fn test_turnstile_max_challenge_age() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "challenge_ts": "2022-02-28T15:14:30.096Z"}"#,
+        );
+        turnstile_config.max_challenge_age = Some(std::time::Duration::from_secs(300));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("stale_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::ChallengeExpired));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_action_by_path() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "action": "login"}"#,
+        );
+        turnstile_config.action_by_path = vec![
+            (ResourceDef::new("/login"), "login".to_string()),
+            (ResourceDef::new("/signup"), "signup".to_string()),
+            (
+                ResourceDef::new("/posts/{id}/comment"),
+                "comment".to_string(),
+            ),
+        ];
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (path, expected) in [
+            ("/login", None),
+            ("/signup", Some("signup")),
+            ("/posts/7/comment", Some("comment")),
+            ("/about", None),
+        ] {
+            let req = test_support::token_request("valid_turnstile_token")
+                .uri(path)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            match expected {
+                None => assert!(resp.status().is_success(), "{} was rejected", path),
+                Some(_) => assert!(
+                    matches!(rejection(&resp), TurnstileError::ActionMismatch(action) if action == "login"),
+                    "{} accepted a login token",
+                    path
+                ),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_min_score() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "score": 0.3}"#,
+        );
+        turnstile_config.min_score = Some(0.5);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("low_score_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::ScoreTooLow(score) if *score == 0.3
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_should_verify_predicate() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.should_verify =
+            Some(Arc::new(|req| req.query_string().contains("sensitive=1")));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // no token needed when the predicate declines
+        let req = test::TestRequest::get().uri("/?sensitive=0").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri("/?sensitive=1")
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_outcome_as_req_data() {
+        async fn handler(outcome: web::ReqData<SiteVerifyResponse>) -> HttpResponse {
+            HttpResponse::Ok().body(outcome.action.clone().unwrap_or_default())
+        }
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "action": "login"}"#,
+        );
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(handler)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "login");
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_rejects_duplicate_token_headers() {
+        let turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("first_token")
+            .append_header(("cf-turnstile-response", "second_token"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::InvalidTokenFormat
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_without_peer_ip() {
+        let (url, requests) = test_support::spawn_recording_siteverify_stub(r#"{"success": true}"#);
+
+        let mut strict = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        strict.verify_url = url.clone();
+
+        let mut lenient = strict.clone();
+        lenient.require_client_ip = false;
+
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/strict")
+                        .wrap(Turnstile::new(strict))
+                        .route("", web::get().to(HttpResponse::Ok)),
+                )
+                .service(
+                    web::scope("/uds")
+                        .wrap(Turnstile::new(lenient))
+                        .route("", web::get().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+
+        // no peer address, as for a Unix domain socket listener
+        let request = |uri| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("cf-turnstile-response", "valid_turnstile_token"))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request("/strict")).await;
+        assert!(matches!(rejection(&resp), TurnstileError::ClientIPNotFound));
+
+        let resp = test::call_service(&app, request("/uds")).await;
+        assert!(resp.status().is_success());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["response"], "valid_turnstile_token");
+        assert!(requests[0].get("remoteip").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_with_client_and_url() {
+        let (url, requests) = test_support::spawn_recording_siteverify_stub(r#"{"success": true}"#);
+        let client = reqwest::Client::builder()
+            .user_agent("custom-agent")
+            .build()
+            .unwrap();
+
+        let turnstile =
+            Turnstile::with_client_and_url("1x0000000000000000000000000000000AA", client, url);
+        assert_eq!(turnstile.config.timeout_secs, None);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(turnstile)
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(
+            requests.lock().unwrap()[0]["secret"],
+            "1x0000000000000000000000000000000AA"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_cancellation_closes_upstream_connection() {
+        let (url, events) = test_support::spawn_hanging_siteverify_stub();
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = url;
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let call = test::call_service(&app, req);
+        // simulate the client going away while siteverify is pending
+        let timed_out =
+            actix_web::rt::time::timeout(std::time::Duration::from_millis(300), call).await;
+        assert!(timed_out.is_err());
+
+        let mut seen = Vec::new();
+        for _ in 0..100 {
+            seen.extend(events.try_iter());
+            if seen.contains(&test_support::StubEvent::Closed) {
+                break;
+            }
+            actix_web::rt::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert_eq!(
+            seen,
+            [
+                test_support::StubEvent::Started,
+                test_support::StubEvent::Closed
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_success_policy() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "action": "signup"}"#,
+        );
+        turnstile_config.success_policy =
+            SuccessPolicy::default().require(policy::SuccessCheck::Action("login".to_string()));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::ActionMismatch(action) if action == "signup"
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_should_verify_body_for_graphql_mutations() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.body_limit = 128;
+        turnstile_config.should_verify_body = Some(Arc::new(|_, body| {
+            body["query"]
+                .as_str()
+                .is_some_and(|query| query.trim_start().starts_with("mutation"))
+        }));
+
+        let app = test::init_service(
+            App::new().wrap(Turnstile::new(turnstile_config)).service(
+                web::resource("/graphql")
+                    .to(|body: web::Bytes| async move { HttpResponse::Ok().body(body) }),
+            ),
+        )
+        .await;
+
+        // queries skip verification and still reach the handler with their body
+        let query = r#"{"query": "{ viewer { id } }"}"#;
+        let req = test::TestRequest::post()
+            .uri("/graphql")
+            .set_payload(query)
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, query);
+
+        let req = test::TestRequest::post()
+            .uri("/graphql")
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .set_payload(r#"{"query": "mutation { deleteAccount }"}"#)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+
+        let req = test::TestRequest::post()
+            .uri("/graphql")
+            .set_payload(format!(r#"{{"query": "{}"}}"#, "a".repeat(200)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_builder_overrides() {
+        let url = test_support::spawn_delayed_siteverify_stub(
+            std::time::Duration::from_secs(2),
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true}"#,
+        );
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = url;
+
+        let turnstile = Turnstile::new(turnstile_config)
+            .timeout(std::time::Duration::from_millis(500))
+            .retries(1)
+            .retry_backoff(std::time::Duration::ZERO);
+        assert_eq!(turnstile.config.timeout_secs, Some(1));
+        assert_eq!(turnstile.config.retries, 1);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(turnstile)
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        match rejection(&resp) {
+            TurnstileError::NetworkError(e) => assert!(e.is_timeout()),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_hostname_matches_request_host() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "hostname": "example.com"}"#,
+        );
+        turnstile_config.verify_hostname_matches_request = true;
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token")
+            .insert_header((header::HOST, "Example.COM:8443"))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test_support::token_request("valid_turnstile_token")
+            .insert_header((header::HOST, "admin.example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::HostnameMismatch(hostname) if hostname == "example.com"
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_config_validate() {
+        let mut config = TurnstileConfig::new("wrong-secret");
+        config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error-codes": ["invalid-input-secret"]}"#,
+        );
+        assert!(matches!(
+            config.validate().await,
+            Err(TurnstileError::InvalidSecret)
+        ));
+
+        // a good secret still fails the dummy token, but for token reasons
+        config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error-codes": ["invalid-input-response"]}"#,
+        );
+        assert!(config.validate().await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_config_validate_rejects_test_secrets() {
+        let mut config = TurnstileConfig::new("live-secret");
+        config.fallback_secret_keys = vec![testing::SECRET_ALWAYS_PASSES.to_string()];
+        config.reject_test_secrets = true;
+        // rejected before siteverify, which is unreachable here
+        config.verify_url = "http://127.0.0.1:9/siteverify".to_string();
+        assert!(matches!(
+            config.validate().await,
+            Err(TurnstileError::TestSecret)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_request_id_propagation() {
+        async fn handler(id: web::ReqData<RequestId>) -> HttpResponse {
+            HttpResponse::Ok().body(id.0.clone())
+        }
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.request_id_header = Some("x-request-id".to_string());
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(handler)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token")
+            .insert_header(("x-request-id", "req-42"))
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "req-42");
+
+        // rejections echo the id, generating one when the client sent none
+        let req = test::TestRequest::get()
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let header = resp
+            .headers()
+            .get("x-request-id")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert_eq!(header.len(), 32);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["request_id"], header.as_str());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_audit_only_forwards_and_reports() {
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_mode = VerifyMode::AuditOnly;
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error-codes": ["invalid-input-response"]}"#,
+        );
+        let sink = reported.clone();
+        turnstile_config.audit_callback = Some(Arc::new(move |record: &AuditRecord| {
+            sink.lock().unwrap().push((
+                record.error.to_string(),
+                record.client_ip.clone(),
+                format!("{} {}", record.method, record.path),
+                record.user_agent.clone(),
+            ));
+        }));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("bad_turnstile_token")
+            .uri("/?page=2")
+            .insert_header((header::USER_AGENT, "curl/8.0"))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        for _ in 0..100 {
+            if !reported.lock().unwrap().is_empty() {
+                break;
+            }
+            actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![(
+                "Turnstile verification failed: invalid-input-response".to_string(),
+                Some("192.168.1.1".to_string()),
+                "GET /".to_string(),
+                Some("curl/8.0".to_string())
+            )]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_token_from_nested_json_field() {
+        let (url, requests) = test_support::spawn_recording_siteverify_stub(r#"{"success": true}"#);
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = url;
+        turnstile_config.token_sources = vec![TokenSource::JsonField("captcha.token".to_string())];
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(|body: String| async move { body })),
+        )
+        .await;
+
+        let payload = r#"{"captcha": {"token": "nested_token"}, "name": "x"}"#;
+        let req = test::TestRequest::post()
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .set_payload(payload)
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, payload);
+        assert_eq!(requests.lock().unwrap()[0]["response"], "nested_token");
+
+        let req = test::TestRequest::post()
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .set_payload("not json")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::InvalidTokenFormat
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_verification_status() {
+        async fn handler(status: web::ReqData<VerificationStatus>) -> HttpResponse {
+            HttpResponse::Ok().body(format!("{:?}", status.into_inner()))
+        }
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+        turnstile_config.should_verify =
+            Some(Arc::new(|req: &ServiceRequest| req.path() != "/public"));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .default_service(web::to(handler)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/public").to_request();
+        assert_eq!(
+            test::call_and_read_body(&app, req).await,
+            r#"Skipped { reason: "should_verify" }"#
+        );
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "Verified");
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_verify_only_when_marked() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_only_when_marked = true;
+
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::resource("/write")
+                        .app_data(VerificationRequired)
+                        .wrap(Turnstile::new(turnstile_config.clone()))
+                        .to(HttpResponse::Ok),
+                )
+                .service(
+                    web::scope("")
+                        .wrap(Turnstile::new(turnstile_config))
+                        .wrap_fn(|req, srv| {
+                            if req.method() == actix_web::http::Method::POST {
+                                req.extensions_mut().insert(VerificationRequired);
+                            }
+                            srv.call(req)
+                        })
+                        .default_service(web::to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/read").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/read")
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+
+        let req = test::TestRequest::get()
+            .uri("/write")
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_skips_requests_with_session() {
+        async fn handler(status: web::ReqData<VerificationStatus>) -> HttpResponse {
+            HttpResponse::Ok().body(format!("{:?}", status.into_inner()))
+        }
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.skip_if_header_present = Some("authorization".to_string());
+        turnstile_config.skip_if_cookie_present = Some("session".to_string());
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .default_service(web::to(handler)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .insert_header(("authorization", "Bearer abc"))
+            .to_request();
+        assert_eq!(
+            test::call_and_read_body(&app, req).await,
+            r#"Skipped { reason: "session" }"#
+        );
+
+        let req = test::TestRequest::get()
+            .cookie(actix_web::cookie::Cookie::new("session", "abc"))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::get()
+            .cookie(actix_web::cookie::Cookie::new("other", "abc"))
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_verify_with() {
+        let (url, requests) = test_support::spawn_recording_siteverify_stub(
+            r#"{"success": true, "action": "signup"}"#,
+        );
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = url;
+        turnstile_config.verify_hostname_matches_request = true;
+        turnstile_config.success_policy =
+            SuccessPolicy::default().require(policy::SuccessCheck::Action("login".to_string()));
+        let turnstile = Turnstile::new(turnstile_config);
+
+        assert!(matches!(
+            turnstile.verify_with("token", Some("192.168.1.1")).await,
+            Err(TurnstileError::ActionMismatch(action)) if action == "signup"
+        ));
+        assert_eq!(requests.lock().unwrap()[0]["remoteip"], "192.168.1.1");
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_enforce_on_hosts() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.enforce_on_hosts = Some(vec!["www.example.com".to_string()]);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .insert_header((header::HOST, "admin.internal:8080"))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::get()
+            .insert_header((header::HOST, "WWW.Example.com"))
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+    }
+
+    #[actix_web::test]
+    async fn test_config_debug_redacts_secrets() {
+        let mut config = TurnstileConfig::new("super-secret-key");
+        config.fallback_secret_keys = vec!["old-secret-key".to_string()];
+
+        let debug = format!("{:?}", Turnstile::new(config));
+        assert!(!debug.contains("super-secret-key"));
+        assert!(!debug.contains("old-secret-key"));
+        assert!(debug.contains(r#"secret_key: "***""#));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_token_ref_in_error_body() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.include_token_ref = true;
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error-codes": ["invalid-input-response"]}"#,
+        );
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("bad_turnstile_token").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let token_ref = body["token_ref"].as_str().unwrap();
+        assert_eq!(token_ref, TokenRef::of("bad_turnstile_token").0);
+        assert_eq!(token_ref.len(), 8);
+        assert!(!body.to_string().contains("bad_turnstile_token"));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_redirects_form_submissions() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.error_format = ErrorFormat::Redirect {
+            location: "/signup".to_string(),
+            forms_only: true,
+        };
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::CONTENT_TYPE, "application/x-www-form-urlencoded"))
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            "/signup?error=captcha_verification_failed"
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_status_visible_to_inner_middleware() {
+        use actix_web::dev::Service;
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+        turnstile_config.should_verify = Some(Arc::new(|req| req.path() != "/public"));
+
+        // registered first, so it runs after Turnstile
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(|req, srv| {
+                    let status = req.extensions().get::<VerificationStatus>().cloned();
+                    let fut = srv.call(req);
+                    async move {
+                        let mut res = fut.await?;
+                        res.headers_mut().insert(
+                            header::HeaderName::from_static("x-status"),
+                            header::HeaderValue::from_str(&format!("{:?}", status)).unwrap(),
+                        );
+                        Ok(res)
+                    }
+                })
+                .wrap(Turnstile::new(turnstile_config))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("x-status").unwrap(), "Some(Verified)");
+
+        let req = test::TestRequest::get().uri("/public").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get("x-status").unwrap(),
+            r#"Some(Skipped { reason: "should_verify" })"#
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_from_app_data() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(turnstile_config))
+                .wrap(Turnstile::from_app_data())
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_from_app_data_picks_up_updates() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+        let shared = web::Data::new(RwLock::new(turnstile_config));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(shared.clone())
+                .wrap(Turnstile::from_app_data())
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        shared.write().unwrap().verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error-codes": ["invalid-input-response"]}"#,
+        );
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::VerificationFailed(_)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_from_app_data_without_config() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::from_app_data())
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let err = app.call(req).await.expect_err("missing config must fail");
+        assert!(matches!(
+            err.as_error::<TurnstileError>(),
+            Some(TurnstileError::ConfigMissing)
+        ));
+        assert_eq!(
+            err.error_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_state_changing_only() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_mode = VerifyMode::StateChangingOnly;
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            let req = test::TestRequest::default()
+                .method(method.clone())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success(), "{} was verified", method);
+        }
+
+        for method in [Method::POST, Method::PUT, Method::PATCH, Method::DELETE] {
+            let req = test::TestRequest::default()
+                .method(method.clone())
+                .peer_addr("192.168.1.1:12345".parse().unwrap())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(
+                matches!(rejection(&resp), TurnstileError::TokenNotFound),
+                "{} wasn't verified",
+                method
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_customize_client() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_header_checking_siteverify_stub("user-agent", "custom-agent");
+        turnstile_config.customize_client =
+            Some(Arc::new(|builder| builder.user_agent("custom-agent")));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config).timeout(std::time::Duration::from_secs(2)))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_multipart_field() {
+        const BODY: &str = "--XyZ\r\n\
+            Content-Disposition: form-data; name=\"cf-turnstile-response\"\r\n\r\n\
+            valid_turnstile_token\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\n\
+            file contents\r\n\
+            --XyZ--\r\n";
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+        turnstile_config.token_sources = vec![TokenSource::MultipartField(
+            "cf-turnstile-response".to_string(),
+        )];
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(|body: web::Bytes| async move { body })),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=XyZ"))
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .set_payload(BODY)
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, BODY);
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_remoteip_resolver() {
+        let (verify_url, requests) =
+            test_support::spawn_recording_siteverify_stub(r#"{"success": true}"#);
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = verify_url;
+        turnstile_config.remoteip_resolver = Some(Arc::new(|req| {
+            req.headers()
+                .get("x-user-ip")
+                .and_then(|ip| ip.to_str().ok())
+                .map(str::to_owned)
+        }));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token")
+            .insert_header(("x-user-ip", "203.0.113.7"))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(requests.lock().unwrap()[0]["remoteip"], "203.0.113.7");
+
+        // the peer address isn't used as a fallback, and garbage counts as missing
+        for ip in [None, Some("not-an-ip")] {
+            let mut req = test_support::token_request("valid_turnstile_token");
+            if let Some(ip) = ip {
+                req = req.insert_header(("x-user-ip", ip));
+            }
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert!(matches!(rejection(&resp), TurnstileError::ClientIPNotFound));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_verify_latency_header() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+        let plain = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config.clone()))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+        turnstile_config.verify_latency_header = true;
+        let timed = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&plain, req).await;
+        assert!(resp.headers().get(VERIFY_LATENCY_HEADER).is_none());
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&timed, req).await;
+        let millis = resp.headers().get(VERIFY_LATENCY_HEADER).unwrap();
+        assert!(millis.to_str().unwrap().parse::<u64>().is_ok());
+
+        // rejections don't carry it
+        let req = test::TestRequest::get()
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&timed, req).await;
+        assert!(resp.headers().get(VERIFY_LATENCY_HEADER).is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_token_from_cookie() {
+        let (verify_url, requests) =
+            test_support::spawn_recording_siteverify_stub(r#"{"success": true}"#);
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = verify_url;
+        turnstile_config.token_sources = vec![TokenSource::Cookie("cf_token".to_string())];
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .cookie(actix_web::cookie::Cookie::new(
+                "cf_token",
+                "valid_turnstile_token",
+            ))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(
+            requests.lock().unwrap()[0]["response"],
+            "valid_turnstile_token"
+        );
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_deadline_header() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_delayed_siteverify_stub(
+            std::time::Duration::from_millis(500),
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true}"#,
+        );
+        turnstile_config.deadline_header = Some("x-deadline".to_string());
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+        let in_millis = |offset: i64| {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64;
+            (now + offset).to_string()
+        };
+
+        for (deadline, passes) in [
+            (in_millis(100), false),
+            (in_millis(-1000), false),
+            (in_millis(5000), true),
+            ("garbage".to_string(), true),
+        ] {
+            let req = test_support::token_request("valid_turnstile_token")
+                .insert_header(("x-deadline", deadline.as_str()))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            if passes {
+                assert!(resp.status().is_success(), "deadline {}", deadline);
+            } else {
+                assert!(matches!(rejection(&resp), TurnstileError::DeadlineExceeded));
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_verified_cookie() {
+        async fn handler(status: web::ReqData<VerificationStatus>) -> HttpResponse {
+            HttpResponse::Ok().body(format!("{:?}", status.into_inner()))
+        }
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+        turnstile_config.verified_cookie = Some(verified_cookie::VerifiedCookie::new(
+            "ts_ok",
+            *b"0123456789abcdef0123456789abcdef",
+            std::time::Duration::from_secs(300),
+        ));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .default_service(web::to(handler)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        let cookie = resp
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == "ts_ok")
+            .unwrap()
+            .into_owned();
+
+        let req = test::TestRequest::get().cookie(cookie).to_request();
+        assert_eq!(
+            test::call_and_read_body(&app, req).await,
+            r#"Skipped { reason: "verified_cookie" }"#
+        );
+
+        let forged = actix_web::cookie::Cookie::new("ts_ok", "99999999999.deadbeef");
+        let req = test::TestRequest::get()
+            .cookie(forged)
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_decide() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "action": "login"}"#,
+        );
+        turnstile_config.should_verify = Some(Arc::new(|req| req.path() != "/health"));
+        let turnstile = Turnstile::new(turnstile_config);
+
+        let mut req = test_support::token_request("valid_turnstile_token").to_srv_request();
+        match turnstile.decide(&mut req).await {
+            VerifyDecision::Allowed(outcome) => {
+                assert_eq!(outcome.action.as_deref(), Some("login"))
+            }
+            other => panic!("Unexpected decision: {:?}", other),
+        }
+
+        let mut req = test::TestRequest::get().uri("/health").to_srv_request();
+        assert!(matches!(
+            turnstile.decide(&mut req).await,
+            VerifyDecision::Skipped("should_verify")
+        ));
+
+        let mut req = test::TestRequest::get()
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_srv_request();
+        assert!(matches!(
+            turnstile.decide(&mut req).await,
+            VerifyDecision::Denied(TurnstileError::TokenNotFound)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_token_store_is_shared_across_instances() {
+        /// Stand-in for an external store, recording the TTL of each entry.
+        #[derive(Default)]
+        struct SharedStore(std::sync::Mutex<HashMap<String, std::time::Duration>>);
+
+        impl token_cache::TokenStore for SharedStore {
+            fn check_and_record<'a>(
+                &'a self,
+                token_hash: &'a str,
+                ttl: std::time::Duration,
+            ) -> futures_util::future::BoxFuture<'a, bool> {
+                let mut entries = self.0.lock().unwrap();
+                let recorded = !entries.contains_key(token_hash);
+                if recorded {
+                    entries.insert(token_hash.to_owned(), ttl);
+                }
+                Box::pin(std::future::ready(recorded))
+            }
+
+            fn forget<'a>(
+                &'a self,
+                token_hash: &'a str,
+            ) -> futures_util::future::BoxFuture<'a, ()> {
+                self.0.lock().unwrap().remove(token_hash);
+                Box::pin(std::future::ready(()))
+            }
+        }
+
+        let store = Arc::new(SharedStore::default());
+        let instance = || {
+            let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+            turnstile_config.verify_url =
+                test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+            turnstile_config.token_store = Some(store.clone());
+            turnstile_config.token_store_ttl = std::time::Duration::from_secs(120);
+            test::init_service(
+                App::new()
+                    .wrap(Turnstile::new(turnstile_config))
+                    .service(web::resource("/").to(HttpResponse::Ok)),
+            )
+        };
+        let first = instance().await;
+        let second = instance().await;
+
+        let req = test_support::token_request("single_use_token").to_request();
+        assert!(test::call_service(&first, req).await.status().is_success());
+        assert_eq!(
+            store.0.lock().unwrap()[&token_cache::token_hash("single_use_token")],
+            std::time::Duration::from_secs(120)
+        );
+
+        let req = test_support::token_request("single_use_token").to_request();
+        let resp = test::call_service(&second, req).await;
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::VerificationFailed(codes) if codes == &["timeout-or-duplicate"]
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_server_sent_events_with_query_token() {
+        async fn events() -> HttpResponse {
+            let messages = ["data: one\n\n", "data: two\n\n"]
+                .map(|message| Ok::<_, Error>(web::Bytes::from_static(message.as_bytes())));
+            HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .streaming(futures_util::stream::iter(messages))
+        }
+
+        let (verify_url, requests) =
+            test_support::spawn_recording_siteverify_stub(r#"{"success": true}"#);
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = verify_url;
+        turnstile_config.token_sources =
+            vec![TokenSource::Query("cf-turnstile-response".to_string())];
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .route("/events", web::get().to(events)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/events?cf-turnstile-response=valid_turnstile_token")
+            .insert_header((header::ACCEPT, "text/event-stream"))
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+        // verified once when the stream opened, then handed through as is
+        assert_eq!(test::read_body(resp).await, "data: one\n\ndata: two\n\n");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        let req = test::TestRequest::get()
+            .uri("/events")
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_rotating_client() {
+        let broken = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all("http://127.0.0.1:1").unwrap())
+            .build()
+            .unwrap();
+        let rotating = reqwest_client::RotatingClient::new(broken);
+
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+        turnstile_config.rotating_client = Some(rotating.clone());
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::NetworkError(_)));
+
+        // the running middleware picks up the replacement
+        rotating.set_client(reqwest_client::build_client(Some(5)));
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_sends_bare_ipv6_remoteip() {
+        let (verify_url, requests) =
+            test_support::spawn_recording_siteverify_stub(r#"{"success": true}"#);
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = verify_url;
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for forwarded_for in ["[2001:db8::1]:443", "[2001:db8::1]"] {
+            let req = test_support::token_request("valid_turnstile_token")
+                .insert_header(("x-forwarded-for", forwarded_for))
+                .to_request();
+            assert!(test::call_service(&app, req).await.status().is_success());
+        }
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        for body in requests.iter() {
+            assert_eq!(body["remoteip"], "2001:db8::1");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_transient_error_codes() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error-codes": ["internal-error"]}"#,
+        );
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config.clone()))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::TransientFailure(codes) if codes == &["internal-error"]
+        ));
+
+        // without the mapping it's an ordinary rejection
+        turnstile_config.transient_error_codes.clear();
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            rejection(&resp),
+            TurnstileError::VerificationFailed(_)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_fail_open_compensation() {
+        async fn reported_after_fail_open(idempotent: bool) -> Vec<String> {
+            let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+            let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+            turnstile_config.verify_url = test_support::spawn_sequenced_siteverify_stub(vec![
+                (StatusCode::SERVICE_UNAVAILABLE, "upstream unavailable"),
+                (
+                    StatusCode::OK,
+                    r#"{"success": false, "error-codes": ["invalid-input-response"]}"#,
+                ),
+            ]);
+            turnstile_config.on_network_error = NetworkErrorPolicy::FailOpen;
+            turnstile_config.retry_backoff = std::time::Duration::from_millis(10);
+            let sink = reported.clone();
+            turnstile_config.fail_open_compensation = Some(Arc::new(move |record| {
+                sink.lock().unwrap().push(format!(
+                    "{} {}: {}",
+                    record.method, record.path, record.error
+                ));
+            }));
+
+            let app = test::init_service(
+                App::new()
+                    .wrap(Turnstile::new(turnstile_config))
+                    .wrap_fn(move |req, srv| {
+                        if idempotent {
+                            req.extensions_mut().insert(Idempotent);
+                        }
+                        srv.call(req)
+                    })
+                    .service(web::resource("/pay").to(HttpResponse::Ok)),
+            )
+            .await;
+
+            let req = test_support::token_request("valid_turnstile_token")
+                .uri("/pay")
+                .to_request();
+            assert!(test::call_service(&app, req).await.status().is_success());
+
+            for _ in 0..25 {
+                if !reported.lock().unwrap().is_empty() {
+                    break;
+                }
+                actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            let reported = reported.lock().unwrap().clone();
+            reported
+        }
+
+        assert_eq!(
+            reported_after_fail_open(true).await,
+            ["GET /pay: Turnstile verification failed: invalid-input-response"]
+        );
+        assert!(reported_after_fail_open(false).await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_config_builder_checks_locally() {
+        let builder = || TurnstileConfig::builder("1x0000000000000000000000000000000AA");
+        let config = builder()
+            .verify_url("http://127.0.0.1:8080/siteverify")
+            .timeout_secs(Some(2))
+            .configure(|config| config.retries = 3)
+            .build()
+            .unwrap();
+        assert_eq!(config.retries, 3);
+        assert!(TurnstileConfig::new("secret").check().is_ok());
+
+        assert_eq!(
+            TurnstileConfig::builder(" ").build().unwrap_err(),
+            ConfigError::EmptySecret
+        );
+        assert_eq!(
+            builder()
+                .configure(|config| config.fallback_secret_keys = vec![String::new()])
+                .build()
+                .unwrap_err(),
+            ConfigError::EmptySecret
+        );
+        for url in [
+            "",
+            "challenges.cloudflare.com/turnstile",
+            "ftp://example.com/",
+            "unix:/tmp/sock",
+        ] {
+            assert_eq!(
+                builder().verify_url(url).build().unwrap_err(),
+                ConfigError::InvalidVerifyUrl(url.to_string())
+            );
+        }
+        assert_eq!(
+            builder().timeout_secs(Some(0)).build().unwrap_err(),
+            ConfigError::ZeroTimeout
+        );
+        assert_eq!(
+            builder().connect_timeout_secs(Some(0)).build().unwrap_err(),
+            ConfigError::ZeroTimeout
+        );
+        assert_eq!(
+            builder()
+                .connect_timeout_secs(Some(1))
+                .configure(|config| config.client = Some(reqwest::Client::new()))
+                .build()
+                .unwrap_err(),
+            ConfigError::ConnectTimeoutWithClient
+        );
+        assert_eq!(
+            builder()
+                .connect_timeout_secs(Some(1))
+                .configure(|config| {
+                    config.rotating_client =
+                        Some(reqwest_client::RotatingClient::new(reqwest::Client::new()))
+                })
+                .build()
+                .unwrap_err(),
+            ConfigError::ConnectTimeoutWithClient
+        );
+        assert_eq!(
+            builder().token_sources(Vec::new()).build().unwrap_err(),
+            ConfigError::NoTokenSources
+        );
+        let header = TokenSource::Header("cf-turnstile-response".to_string());
+        assert!(matches!(
+            builder()
+                .token_sources(vec![
+                    header.clone(),
+                    TokenSource::Cookie("ts".to_string()),
+                    header
+                ])
+                .build(),
+            Err(ConfigError::DuplicateTokenSource(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_config_builder_rejects_test_secrets() {
+        let reject = |config: &mut TurnstileConfig| config.reject_test_secrets = true;
+        assert_eq!(
+            TurnstileConfig::builder(testing::SECRET_ALWAYS_PASSES)
+                .configure(reject)
+                .build()
+                .unwrap_err(),
+            ConfigError::TestSecret
+        );
+        assert_eq!(
+            TurnstileConfig::builder("0x4AAAAAAAproductionsecret")
+                .configure(|config| {
+                    reject(config);
+                    config.fallback_secret_keys = vec![testing::SECRET_TOKEN_SPENT.to_string()];
+                })
+                .build()
+                .unwrap_err(),
+            ConfigError::TestSecret
+        );
+        assert!(TurnstileConfig::builder("0x4AAAAAAAproductionsecret")
+            .configure(reject)
+            .build()
+            .is_ok());
+        // only with the flag set
+        assert!(TurnstileConfig::builder(testing::SECRET_ALWAYS_PASSES)
+            .build()
+            .is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_kill_switch() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let enabled = Arc::new(AtomicBool::new(true));
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.enabled = Some(enabled.clone());
+
+        let app = test::init_service(App::new().wrap(Turnstile::new(turnstile_config)).service(
+            web::resource("/").to(|status: web::ReqData<VerificationStatus>| async move {
+                HttpResponse::Ok().body(format!("{:?}", status.into_inner()))
+            }),
+        ))
+        .await;
+        let request = || {
+            test::TestRequest::get()
+                .uri("/")
+                .peer_addr("192.168.1.1:12345".parse().unwrap())
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request()).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+
+        enabled.store(false, Ordering::Relaxed);
+        let resp = test::call_service(&app, request()).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            test::read_body(resp).await,
+            r#"Skipped { reason: "disabled" }"#
+        );
+
+        enabled.store(true, Ordering::Relaxed);
+        let resp = test::call_service(&app, request()).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_token_query_fallback() {
+        let (verify_url, requests) =
+            test_support::spawn_recording_siteverify_stub(r#"{"success": true}"#);
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = verify_url;
+        turnstile_config.token_query_fallback = Some("cf-turnstile-response".to_string());
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // the header wins when present
+        let req = test_support::token_request("header_token")
+            .uri("/submit?cf-turnstile-response=query_token")
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/submit?cf-turnstile-response=query_token")
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/submit")
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(matches!(rejection(&resp), TurnstileError::TokenNotFound));
+
+        let tokens: Vec<_> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|body| body["response"].clone())
+            .collect();
+        assert_eq!(tokens, ["header_token", "query_token"]);
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_challenge_round_trip() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+        turnstile_config.error_format = ErrorFormat::Challenge {
+            challenge_path: "/challenge".to_string(),
+            return_param: "return_to".to_string(),
+        };
+        turnstile_config.token_query_fallback = Some("cf-turnstile-response".to_string());
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .peer_addr("192.168.1.1:12345".parse().unwrap())
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, get("/account?tab=1")).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp.headers().get(header::LOCATION).unwrap();
+        assert_eq!(
+            location,
+            "/challenge?return_to=%2Faccount%3Ftab%3D1&error=captcha_verification_failed"
+        );
+
+        // the challenge page is served without a token
+        let location = location.to_str().unwrap().to_owned();
+        let resp = test::call_service(&app, get(&location)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // and its widget submits back to the original destination
+        let resp = test::call_service(
+            &app,
+            get("/account?tab=1&cf-turnstile-response=valid_turnstile_token"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_token_store_claims_atomically() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_delayed_siteverify_stub(
+            std::time::Duration::from_millis(200),
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true}"#,
+        );
+        turnstile_config.token_store = Some(Arc::new(TokenCache::new(16)));
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // both are in flight at once; only one may pass
+        let (first, second) = futures_util::join!(
+            test::call_service(
+                &app,
+                test_support::token_request("single_use_token").to_request()
+            ),
+            test::call_service(
+                &app,
+                test_support::token_request("single_use_token").to_request()
+            ),
+        );
+        let passed = [&first, &second]
+            .iter()
+            .filter(|resp| resp.status().is_success())
+            .count();
+        assert_eq!(passed, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_turnstile_token_store_forgets_unaccepted_tokens() {
+        let mut turnstile_config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        turnstile_config.verify_url = test_support::spawn_sequenced_siteverify_stub(vec![
+            (StatusCode::BAD_GATEWAY, "<html>bad gateway</html>"),
+            (StatusCode::OK, r#"{"success": true}"#),
+        ]);
+        turnstile_config.retries = 0;
+        let store = TokenCache::new(16);
+        turnstile_config.token_store = Some(Arc::new(store.clone()));
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(turnstile_config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(rejection(&resp).is_upstream());
+        assert!(store.is_empty());
+
+        let req = test_support::token_request("valid_turnstile_token").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(store.len(), 1);
+    }
 }
@@ -0,0 +1,191 @@
+use std::time::Instant;
+
+use crate::{error::TurnstileError, turnstile::SiteVerifyResponse, LogLevels, RequestId, TokenRef};
+
+/// Logs the outcome and latency of one verification through the `log` facade,
+/// tagged with the [`RequestId`] (or `-`) so it can be traced to the request.
+///
+/// Dropped without [`finish`](Self::finish) — i.e. the request was cancelled
+/// mid-verification — it still logs the time spent.
+pub(crate) struct VerifyTimer {
+    started: Instant,
+    request_id: Option<String>,
+    finished: bool,
+}
+
+impl VerifyTimer {
+    pub(crate) fn start(request_id: Option<&RequestId>) -> Self {
+        Self {
+            started: Instant::now(),
+            request_id: request_id.map(|id| id.0.clone()),
+            finished: false,
+        }
+    }
+
+    fn request_id(&self) -> &str {
+        self.request_id.as_deref().unwrap_or("-")
+    }
+
+    pub(crate) fn finish(
+        mut self,
+        result: &Result<SiteVerifyResponse, TurnstileError>,
+        token_ref: Option<&TokenRef>,
+        levels: &LogLevels,
+    ) {
+        self.finished = true;
+        let elapsed = self.started.elapsed();
+        let request_id = self.request_id();
+        let token_ref = token_ref.map_or("-", |token_ref| &token_ref.0);
+
+        match result {
+            Ok(_) => log::log!(
+                levels.passed,
+                "turnstile verification passed in {:?} (request {}, token {})",
+                elapsed,
+                request_id,
+                token_ref
+            ),
+            Err(err)
+                if err.is_upstream()
+                    || matches!(
+                        err,
+                        TurnstileError::InvalidSecret | TurnstileError::TestSecret
+                    ) =>
+            {
+                log::log!(
+                    levels.unavailable,
+                    "turnstile verification failed in {:?} (request {}, token {}): {}",
+                    elapsed,
+                    request_id,
+                    token_ref,
+                    err
+                )
+            }
+            Err(err) => {
+                let level = match err {
+                    TurnstileError::TokenNotFound => levels.token_missing,
+                    _ => levels.rejected,
+                };
+                log::log!(
+                    level,
+                    "turnstile verification rejected in {:?} (request {}, token {}): {}",
+                    elapsed,
+                    request_id,
+                    token_ref,
+                    err
+                )
+            }
+        }
+    }
+}
+
+impl Drop for VerifyTimer {
+    fn drop(&mut self) {
+        if !self.finished {
+            log::debug!(
+                "turnstile verification cancelled after {:?} (request {})",
+                self.started.elapsed(),
+                self.request_id()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // (thread, level, message); other tests running in parallel log too
+    static RECORDS: Mutex<Vec<(std::thread::ThreadId, log::Level, String)>> =
+        Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            RECORDS.lock().unwrap().push((
+                std::thread::current().id(),
+                record.level(),
+                record.args().to_string(),
+            ));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_verify_timer_logs() {
+        log::set_logger(&CaptureLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let levels = LogLevels::default();
+        let token_ref = TokenRef::of("token");
+        let request_id = RequestId("req-42".to_string());
+        VerifyTimer::start(Some(&request_id)).finish(
+            &Ok(SiteVerifyResponse::default()),
+            Some(&token_ref),
+            &levels,
+        );
+        VerifyTimer::start(Some(&request_id)).finish(
+            &Err(TurnstileError::RateLimited { retry_after: None }),
+            None,
+            &levels,
+        );
+        drop(VerifyTimer::start(Some(&request_id)));
+
+        let levels = LogLevels {
+            passed: log::Level::Trace,
+            token_missing: log::Level::Debug,
+            rejected: log::Level::Info,
+            unavailable: log::Level::Error,
+        };
+        VerifyTimer::start(None).finish(&Ok(SiteVerifyResponse::default()), None, &levels);
+        VerifyTimer::start(None).finish(&Err(TurnstileError::TokenNotFound), None, &levels);
+        VerifyTimer::start(None).finish(
+            &Err(TurnstileError::VerificationFailed(vec![])),
+            None,
+            &levels,
+        );
+        VerifyTimer::start(None).finish(&Err(TurnstileError::InvalidSecret), None, &levels);
+
+        let current = std::thread::current().id();
+        let records: Vec<_> = RECORDS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(thread, ..)| *thread == current)
+            .map(|(_, level, message)| (*level, message.clone()))
+            .collect();
+        assert_eq!(records.len(), 7);
+        assert_eq!(records[0].0, log::Level::Debug);
+        assert!(records[0].1.starts_with("turnstile verification passed in"));
+        assert!(records[0]
+            .1
+            .ends_with(&format!("(request req-42, token {})", token_ref.0)));
+        assert_eq!(records[1].0, log::Level::Warn);
+        assert!(records[1].1.contains("rate limit exceeded"));
+        assert!(records[1].1.contains("(request req-42, token -)"));
+        assert!(records[2]
+            .1
+            .starts_with("turnstile verification cancelled after"));
+        assert!(records[2].1.ends_with("(request req-42)"));
+        assert!(records[3].1.contains("(request -, token -)"));
+
+        let levels: Vec<_> = records[3..].iter().map(|(level, _)| *level).collect();
+        assert_eq!(
+            levels,
+            [
+                log::Level::Trace,
+                log::Level::Debug,
+                log::Level::Info,
+                log::Level::Error
+            ]
+        );
+    }
+}
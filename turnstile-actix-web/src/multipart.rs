@@ -0,0 +1,209 @@
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    web::{Bytes, BytesMut},
+    HttpMessage,
+};
+use futures_util::StreamExt;
+
+use crate::error::TurnstileError;
+
+/// Reads a `multipart/form-data` body just far enough to find the text field
+/// `name` and returns its value; `Ok(None)` if the body has no such field or
+/// isn't multipart. At most `limit` bytes are buffered while searching.
+///
+/// The handler still receives the full body: the bytes read here, followed by
+/// the rest of the stream, which is never buffered.
+pub(crate) async fn find_field(
+    req: &mut ServiceRequest,
+    name: &str,
+    limit: usize,
+) -> Result<Option<String>, TurnstileError> {
+    let Some(boundary) = boundary(req) else {
+        return Ok(None);
+    };
+
+    let mut payload = req.take_payload();
+    let mut read = BytesMut::new();
+    let result = loop {
+        match scan(&read, &boundary, name) {
+            Scan::Found(value) => break value.map(Some),
+            Scan::Absent => break Ok(None),
+            Scan::NeedMore => {}
+        }
+        match payload.next().await {
+            None => break Ok(None),
+            Some(Err(err)) => break Err(err.into()),
+            Some(Ok(chunk)) => {
+                // keep the chunk either way, it's handed on below
+                let too_large = read.len() + chunk.len() > limit;
+                read.extend_from_slice(&chunk);
+                if too_large {
+                    break Err(TurnstileError::PayloadTooLarge);
+                }
+            }
+        }
+    };
+
+    let read: Bytes = read.freeze();
+    let rest = futures_util::stream::once(async move { Ok(read) }).chain(payload);
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(rest),
+    });
+    result
+}
+
+/// The `boundary` parameter of a `multipart/form-data` content type.
+fn boundary(req: &ServiceRequest) -> Option<String> {
+    let mime = req.mime_type().ok()??;
+    if mime.essence_str() != "multipart/form-data" {
+        return None;
+    }
+    mime.get_param("boundary").map(|b| b.as_str().to_owned())
+}
+
+enum Scan {
+    Found(Result<String, TurnstileError>),
+    Absent,
+    NeedMore,
+}
+
+/// Looks for the field in the body read so far.
+fn scan(body: &[u8], boundary: &str, name: &str) -> Scan {
+    let delimiter = format!("--{}", boundary);
+    let Some(start) = find(body, delimiter.as_bytes()) else {
+        return Scan::NeedMore;
+    };
+    let close = format!("\r\n--{}", boundary);
+
+    let mut rest = &body[start + delimiter.len()..];
+    loop {
+        // a delimiter is followed by `--` at the end of the body, else a part
+        if rest.len() < 2 {
+            return Scan::NeedMore;
+        }
+        let Some(part) = rest.strip_prefix(b"\r\n") else {
+            return Scan::Absent;
+        };
+        let Some(headers_end) = find(part, b"\r\n\r\n") else {
+            return Scan::NeedMore;
+        };
+        let content = &part[headers_end + 4..];
+        let Some(content_end) = find(content, close.as_bytes()) else {
+            return Scan::NeedMore;
+        };
+
+        if field_name(&part[..headers_end]) == Some(name) {
+            let value = std::str::from_utf8(&content[..content_end])
+                .map(str::to_owned)
+                .map_err(|_| TurnstileError::InvalidTokenFormat);
+            return Scan::Found(value);
+        }
+        rest = &content[content_end + close.len()..];
+    }
+}
+
+/// The `name` of a part, from its `Content-Disposition: form-data` header.
+fn field_name(headers: &[u8]) -> Option<&str> {
+    let headers = std::str::from_utf8(headers).ok()?;
+    let disposition = headers.split("\r\n").find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+        header
+            .trim()
+            .eq_ignore_ascii_case("content-disposition")
+            .then_some(value)
+    })?;
+
+    disposition.split(';').find_map(|param| {
+        let value = param.trim().strip_prefix("name=")?;
+        Some(value.trim_matches('"'))
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    const BODY: &str = "--XyZ\r\n\
+        Content-Disposition: form-data; name=\"message\"\r\n\r\n\
+        hello\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"cf-turnstile-response\"\r\n\r\n\
+        abc\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        file contents\r\n\
+        --XyZ--\r\n";
+
+    fn multipart_request(body: &'static str) -> ServiceRequest {
+        TestRequest::post()
+            .insert_header(("content-type", "multipart/form-data; boundary=XyZ"))
+            .set_payload(body)
+            .to_srv_request()
+    }
+
+    #[actix_web::test]
+    async fn test_find_field() {
+        let mut req = multipart_request(BODY);
+        let token = find_field(&mut req, "cf-turnstile-response", 1024).await;
+        assert_eq!(token.unwrap().as_deref(), Some("abc"));
+
+        // the handler still gets the whole body
+        let body = actix_web::body::to_bytes(actix_web::body::BodyStream::new(req.take_payload()))
+            .await
+            .unwrap();
+        assert_eq!(body, BODY);
+
+        let mut req = multipart_request(BODY);
+        let missing = find_field(&mut req, "token", 1024).await;
+        assert_eq!(missing.unwrap(), None);
+
+        // `filename` isn't mistaken for `name`
+        let mut req = multipart_request(BODY);
+        let missing = find_field(&mut req, "a.txt", 1024).await;
+        assert_eq!(missing.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_find_field_limit() {
+        let mut req = multipart_request(BODY);
+        assert!(matches!(
+            find_field(&mut req, "cf-turnstile-response", 16).await,
+            Err(TurnstileError::PayloadTooLarge)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_find_field_ignores_other_bodies() {
+        let mut req = TestRequest::post()
+            .insert_header(("content-type", "application/json"))
+            .set_payload(r#"{"cf-turnstile-response": "abc"}"#)
+            .to_srv_request();
+        assert_eq!(
+            find_field(&mut req, "cf-turnstile-response", 1024)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_scan_split_input() {
+        // every prefix of the body either finds the field or asks for more
+        for end in 0..BODY.len() {
+            match scan(&BODY.as_bytes()[..end], "XyZ", "cf-turnstile-response") {
+                Scan::Found(value) => assert_eq!(value.unwrap(), "abc"),
+                Scan::NeedMore => {}
+                Scan::Absent => panic!("field reported absent after {} bytes", end),
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::future::Future;
+
+use opentelemetry::{
+    context::FutureExt,
+    global,
+    propagation::Extractor,
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+
+use crate::{error::TurnstileError, TurnstileConfig};
+
+/// Instrumentation scope of the spans.
+const TRACER_NAME: &str = "turnstile-actix-web";
+
+/// The trace context an incoming request carries, read with the global
+/// propagator. Without one, the current context (e.g. set by an OpenTelemetry
+/// middleware further out) stays the parent.
+pub(crate) fn incoming_context(headers: &dyn Extractor) -> Context {
+    global::get_text_map_propagator(|propagator| {
+        propagator.extract_with_context(&Context::current(), headers)
+    })
+}
+
+/// Request headers of an actix request, for [`incoming_context`].
+pub(crate) struct ActixHeaders<'a>(pub(crate) &'a actix_web::http::header::HeaderMap);
+
+impl Extractor for ActixHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Request headers of an [`http`] request, for [`incoming_context`].
+#[cfg(feature = "tower")]
+pub(crate) struct HttpHeaders<'a>(pub(crate) &'a http::HeaderMap);
+
+#[cfg(feature = "tower")]
+impl Extractor for HttpHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Runs one siteverify call in a client span, a child of the current
+/// context, with the HTTP semantic-convention attributes. The status code is
+/// added by [`record_status`] once the response is in.
+pub(crate) async fn client_span<T>(
+    config: &TurnstileConfig,
+    call: impl Future<Output = Result<T, TurnstileError>>,
+) -> Result<T, TurnstileError> {
+    let tracer = global::tracer(TRACER_NAME);
+    let mut attributes = vec![KeyValue::new("http.method", "POST")];
+    let url = reqwest::Url::parse(&config.verify_url).ok();
+    if let Some(host) = url.as_ref().and_then(|url| url.host_str()) {
+        attributes.push(KeyValue::new("server.address", host.to_owned()));
+    }
+    let span = tracer
+        .span_builder("POST")
+        .with_kind(SpanKind::Client)
+        .with_attributes(attributes)
+        .start(&tracer);
+
+    let cx = Context::current_with_span(span);
+    let result = call.with_context(cx.clone()).await;
+
+    let span = cx.span();
+    if let Err(err) = &result {
+        span.set_attribute(KeyValue::new("error.type", error_type(err)));
+        span.set_status(Status::error(err.to_string()));
+    }
+    span.end();
+    result
+}
+
+/// Adds the siteverify response status to the current client span.
+pub(crate) fn record_status(status: u16) {
+    Context::current()
+        .span()
+        .set_attribute(KeyValue::new("http.status_code", i64::from(status)));
+}
+
+/// A low-cardinality `error.type`: the status code when the call got an HTTP
+/// error, the kind of failure otherwise.
+fn error_type(err: &TurnstileError) -> String {
+    match err {
+        TurnstileError::NetworkError(err) => match err.status() {
+            Some(status) => status.as_str().to_owned(),
+            None if err.is_timeout() => "timeout".to_owned(),
+            None => "network_error".to_owned(),
+        },
+        TurnstileError::RateLimited { .. } => "429".to_owned(),
+        TurnstileError::MalformedResponse(_) => "malformed_response".to_owned(),
+        TurnstileError::BackendError(_) => "backend_error".to_owned(),
+        TurnstileError::RuntimeUnavailable => "runtime_unavailable".to_owned(),
+        _ => "_OTHER".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+    use opentelemetry::{
+        trace::{SpanId, TraceId},
+        Value,
+    };
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator,
+        trace::{InMemorySpanExporter, SdkTracerProvider, SpanData},
+    };
+
+    use super::*;
+    use crate::{test_support, Turnstile};
+
+    /// Installs an in-memory exporter and the W3C propagator globally, once
+    /// for all tests; they tell their spans apart by trace id.
+    fn exporter() -> &'static InMemorySpanExporter {
+        static EXPORTER: OnceLock<InMemorySpanExporter> = OnceLock::new();
+        EXPORTER.get_or_init(|| {
+            let exporter = InMemorySpanExporter::default();
+            let provider = SdkTracerProvider::builder()
+                .with_simple_exporter(exporter.clone())
+                .build();
+            global::set_tracer_provider(provider);
+            global::set_text_map_propagator(TraceContextPropagator::new());
+            exporter
+        })
+    }
+
+    /// Verifies a token through the middleware in a request carrying
+    /// `traceparent` for `trace_id`, returning the spans of that trace.
+    async fn traced_spans(verify_url: String, trace_id: &str) -> Vec<SpanData> {
+        let exporter = exporter();
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = verify_url;
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(config))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let traceparent = format!("00-{}-00f067aa0ba902b7-01", trace_id);
+        let req = test_support::token_request("valid_turnstile_token")
+            .insert_header(("traceparent", traceparent))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let trace_id = TraceId::from_hex(trace_id).unwrap();
+        let mut spans = exporter.get_finished_spans().unwrap();
+        spans.retain(|span| span.span_context.trace_id() == trace_id);
+        spans
+    }
+
+    fn attribute<'a>(span: &'a SpanData, key: &str) -> Option<&'a Value> {
+        let attribute = span.attributes.iter().find(|kv| kv.key.as_str() == key);
+        attribute.map(|kv| &kv.value)
+    }
+
+    #[actix_web::test]
+    async fn test_client_span_continues_incoming_trace() {
+        let verify_url =
+            test_support::spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+        let spans = traced_spans(verify_url, "4bf92f3577b34da6a3ce929d0e0e4736").await;
+
+        assert_eq!(spans.len(), 1);
+        let span = &spans[0];
+        assert_eq!(span.name, "POST");
+        assert_eq!(span.span_kind, SpanKind::Client);
+        assert_eq!(
+            span.parent_span_id,
+            SpanId::from_hex("00f067aa0ba902b7").unwrap()
+        );
+        assert_eq!(attribute(span, "http.method"), Some(&Value::from("POST")));
+        assert_eq!(
+            attribute(span, "server.address"),
+            Some(&Value::from("127.0.0.1"))
+        );
+        assert_eq!(attribute(span, "http.status_code"), Some(&Value::I64(200)));
+        assert_eq!(attribute(span, "error.type"), None);
+        assert_eq!(span.status, Status::Unset);
+    }
+
+    #[actix_web::test]
+    async fn test_client_span_records_errors() {
+        let verify_url = test_support::spawn_siteverify_stub(
+            StatusCode::BAD_GATEWAY,
+            vec![("content-type", "text/html")],
+            "<html>bad gateway</html>",
+        );
+        let spans = traced_spans(verify_url, "0af7651916cd43dd8448eb211c80319c").await;
+
+        assert_eq!(spans.len(), 1);
+        let span = &spans[0];
+        assert_eq!(attribute(span, "http.status_code"), Some(&Value::I64(502)));
+        assert_eq!(attribute(span, "error.type"), Some(&Value::from("502")));
+        assert!(matches!(span.status, Status::Error { .. }));
+    }
+}
@@ -0,0 +1,227 @@
+//! Token extraction and verification from plain [`http`] request parts, for
+//! callers outside actix.
+//!
+//! Works like the middleware, except that there is no body to read, so
+//! [`TokenSource::JsonField`] and [`TokenSource::MultipartField`] sources
+//! count as absent, and `hostname_validator` isn't applied.
+
+use std::{net::SocketAddr, time::SystemTime};
+
+use http::{
+    header::{COOKIE, HOST},
+    HeaderMap, Uri,
+};
+
+use crate::{
+    check_outcome, client_ip,
+    error::TurnstileError,
+    guard_replay, host_name, parse_deadline,
+    token_source::{cookie_token, header_token, query_token, TokenPick, TokenSource},
+    turnstile::{verify_cloudflare_turnstile_full, SiteVerifyResponse},
+    within_deadline, RequestView, TurnstileConfig,
+};
+
+/// Extracts the token and client IP from `headers` and `peer` (the TCP peer
+/// address, if known) as configured, then verifies the token. Without the
+/// URI, [`TokenSource::Query`] sources and `token_query_fallback` count as
+/// absent too.
+pub async fn verify_request_parts(
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    RequestParts::read(headers, None, peer, config)
+        .verify(config)
+        .await
+}
+
+/// What verification needs from a request, owned, so verifying doesn't
+/// borrow the request.
+pub(crate) struct RequestParts {
+    token: Result<String, TurnstileError>,
+    client_ip: Option<String>,
+    host: Option<String>,
+    /// Only known when the caller has the URI, for `action_by_path`.
+    path: Option<String>,
+    deadline: Option<SystemTime>,
+}
+
+impl RequestParts {
+    pub(crate) fn read(
+        headers: &HeaderMap,
+        uri: Option<&Uri>,
+        peer: Option<SocketAddr>,
+        config: &TurnstileConfig,
+    ) -> Self {
+        Self {
+            token: read_token(
+                headers,
+                uri.map(|uri| uri.query().unwrap_or_default()),
+                config,
+            ),
+            client_ip: client_ip::resolve_from_parts(headers, peer, &config.ip_source),
+            host: headers
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .and_then(host_name)
+                .map(str::to_owned),
+            path: uri.map(|uri| uri.path().to_owned()),
+            deadline: config
+                .deadline_header
+                .as_ref()
+                .and_then(|header| parse_deadline(headers.get(header)?.to_str().ok()?)),
+        }
+    }
+
+    pub(crate) async fn verify(
+        self,
+        config: &TurnstileConfig,
+    ) -> Result<SiteVerifyResponse, TurnstileError> {
+        if self.client_ip.is_none() && config.require_client_ip {
+            return Err(TurnstileError::ClientIPNotFound);
+        }
+        let token = self.token?;
+
+        guard_replay(&token, config, async {
+            let outcome = within_deadline(
+                self.deadline,
+                verify_cloudflare_turnstile_full(&token, self.client_ip.as_deref(), config),
+            )
+            .await?;
+            let request = RequestView::Detached {
+                host: self.host.as_deref(),
+                path: self.path.as_deref(),
+            };
+            check_outcome(&outcome, request, config)?;
+            Ok(outcome)
+        })
+        .await
+    }
+}
+
+/// The token as the middleware would pick it, from the sources that live in
+/// the request head.
+fn read_token(
+    headers: &HeaderMap,
+    query: Option<&str>,
+    config: &TurnstileConfig,
+) -> Result<String, TurnstileError> {
+    let mut pick = TokenPick::new(config);
+    for source in &config.token_sources {
+        let token = match (source, query) {
+            (TokenSource::Header(name), _) => header_token(
+                headers
+                    .get_all(name.as_str())
+                    .iter()
+                    .map(|value| value.to_str().ok()),
+            )?,
+            (TokenSource::Cookie(name), _) => cookie_token(
+                headers.get_all(COOKIE).iter().map(|value| value.as_bytes()),
+                name,
+            )?,
+            (TokenSource::Query(name), Some(query)) => query_token(query, name)?,
+            _ => None,
+        };
+        if pick.offer(token)? {
+            break;
+        }
+    }
+    if let (Some(name), Some(query)) = (pick.fallback(), query) {
+        pick.offer(query_token(query, name)?)?;
+    }
+    pick.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+
+    use super::*;
+    use crate::test_support::spawn_recording_siteverify_stub;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (http::HeaderName::from_static(name), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn test_verify_request_parts() {
+        let (verify_url, requests) = spawn_recording_siteverify_stub(r#"{"success": true}"#);
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = verify_url;
+        let peer = Some("192.168.1.1:12345".parse().unwrap());
+
+        let headers = headers(&[("cf-turnstile-response", "valid_turnstile_token")]);
+        let outcome = verify_request_parts(&headers, peer, &config).await.unwrap();
+        assert!(outcome.success);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0]["response"], "valid_turnstile_token");
+        assert_eq!(requests[0]["remoteip"], "192.168.1.1");
+    }
+
+    #[actix_web::test]
+    async fn test_verify_request_parts_rejections() {
+        let config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        let peer = Some("192.168.1.1:12345".parse().unwrap());
+
+        let err = verify_request_parts(&HeaderMap::new(), peer, &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TurnstileError::TokenNotFound));
+
+        let headers = headers(&[("cf-turnstile-response", "valid_turnstile_token")]);
+        let err = verify_request_parts(&headers, None, &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TurnstileError::ClientIPNotFound));
+    }
+
+    #[actix_web::test]
+    async fn test_verify_request_parts_checks_host() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = crate::test_support::spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "hostname": "example.com"}"#,
+        );
+        config.verify_hostname_matches_request = true;
+        let peer = Some("192.168.1.1:12345".parse().unwrap());
+
+        let headers = headers(&[
+            ("cf-turnstile-response", "valid_turnstile_token"),
+            ("host", "other.example:8080"),
+        ]);
+        let err = verify_request_parts(&headers, peer, &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TurnstileError::HostnameMismatch(_)));
+    }
+
+    #[actix_web::test]
+    async fn test_request_parts_token_sources() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.token_sources = vec![
+            TokenSource::JsonField("token".to_string()),
+            TokenSource::Cookie("cf_token".to_string()),
+        ];
+        config.token_query_fallback = Some("cf-turnstile-response".to_string());
+        let uri: Uri = "/submit?cf-turnstile-response=from_query".parse().unwrap();
+
+        let token =
+            |headers: &HeaderMap, uri| RequestParts::read(headers, uri, None, &config).token;
+        let cookie = headers(&[("cookie", "session=1; cf_token=from%20cookie")]);
+        assert_eq!(token(&cookie, Some(&uri)).unwrap(), "from cookie");
+        assert_eq!(token(&HeaderMap::new(), Some(&uri)).unwrap(), "from_query");
+        assert!(matches!(
+            token(&HeaderMap::new(), None),
+            Err(TurnstileError::TokenNotFound)
+        ));
+        let twice = headers(&[("cookie", "cf_token=a; cf_token=b")]);
+        assert!(matches!(
+            token(&twice, Some(&uri)),
+            Err(TurnstileError::InvalidTokenFormat)
+        ));
+    }
+}
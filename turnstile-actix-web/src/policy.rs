@@ -0,0 +1,262 @@
+use std::{fmt, sync::Arc};
+
+use crate::{error::TurnstileError, turnstile::SiteVerifyResponse};
+
+/// A single acceptance criterion applied to a parsed siteverify response.
+#[derive(Clone, Debug)]
+pub enum SuccessCheck {
+    /// `success` must be `true`.
+    Success,
+    /// `error-codes` must be empty.
+    NoErrorCodes,
+    /// `action` must equal the given value.
+    Action(String),
+    /// `hostname` must be one of the given hostnames (case-insensitive).
+    Hostname(Vec<String>),
+    /// `cdata` must equal the given value.
+    Cdata(String),
+    /// The challenge must have been solved within the given duration.
+    #[cfg(any(feature = "chrono", feature = "time"))]
+    MaxChallengeAge(std::time::Duration),
+    /// `score` must be at least the given value. Responses without a score
+    /// (non-Enterprise) pass.
+    MinScore(f64),
+    /// A caller-supplied check; its error is returned as is.
+    Custom(CustomCheck),
+}
+
+/// Caller-supplied acceptance logic over the full siteverify response.
+pub type CheckFn = Arc<dyn Fn(&SiteVerifyResponse) -> Result<(), TurnstileError> + Send + Sync>;
+
+/// A [`CheckFn`] as a [`SuccessCheck`].
+#[derive(Clone)]
+pub struct CustomCheck(pub CheckFn);
+
+impl CustomCheck {
+    pub fn new(
+        check: impl Fn(&SiteVerifyResponse) -> Result<(), TurnstileError> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(check))
+    }
+}
+
+impl fmt::Debug for CustomCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomCheck(..)")
+    }
+}
+
+/// Ordered list of [`SuccessCheck`]s. Evaluation stops at the first failing
+/// check and returns its error.
+///
+/// The default policy only requires `success == true`.
+#[derive(Clone, Debug)]
+pub struct SuccessPolicy {
+    checks: Vec<SuccessCheck>,
+}
+
+impl Default for SuccessPolicy {
+    fn default() -> Self {
+        Self {
+            checks: vec![SuccessCheck::Success],
+        }
+    }
+}
+
+impl SuccessPolicy {
+    /// A policy without any checks; every response is accepted.
+    pub fn empty() -> Self {
+        Self { checks: Vec::new() }
+    }
+
+    /// A policy consisting only of `check`, which alone decides acceptance;
+    /// it must test `success` itself.
+    pub fn custom(
+        check: impl Fn(&SiteVerifyResponse) -> Result<(), TurnstileError> + Send + Sync + 'static,
+    ) -> Self {
+        Self::empty().require(SuccessCheck::Custom(CustomCheck::new(check)))
+    }
+
+    /// Appends `check`, to be evaluated after the existing ones.
+    pub fn require(mut self, check: SuccessCheck) -> Self {
+        self.checks.push(check);
+        self
+    }
+
+    pub fn checks(&self) -> &[SuccessCheck] {
+        &self.checks
+    }
+
+    pub fn evaluate(&self, outcome: &SiteVerifyResponse) -> Result<(), TurnstileError> {
+        self.checks
+            .iter()
+            .try_for_each(|check| check.evaluate(outcome))
+    }
+}
+
+impl SuccessCheck {
+    pub fn evaluate(&self, outcome: &SiteVerifyResponse) -> Result<(), TurnstileError> {
+        match self {
+            SuccessCheck::Success if !outcome.success => Err(TurnstileError::VerificationFailed(
+                outcome.error_codes.clone(),
+            )),
+            SuccessCheck::NoErrorCodes if !outcome.error_codes.is_empty() => Err(
+                TurnstileError::VerificationFailed(outcome.error_codes.clone()),
+            ),
+            SuccessCheck::Action(expected) if outcome.action.as_deref() != Some(expected) => Err(
+                TurnstileError::ActionMismatch(outcome.action.clone().unwrap_or_default()),
+            ),
+            SuccessCheck::Hostname(allowed) => check_hostname_in(allowed, outcome),
+            SuccessCheck::Custom(check) => (check.0)(outcome),
+            SuccessCheck::MinScore(min) => match outcome.score {
+                Some(score) if score < *min => Err(TurnstileError::ScoreTooLow(score)),
+                _ => Ok(()),
+            },
+            SuccessCheck::Cdata(expected) if outcome.cdata.as_deref() != Some(expected) => {
+                Err(TurnstileError::CdataMismatch)
+            }
+            #[cfg(any(feature = "chrono", feature = "time"))]
+            SuccessCheck::MaxChallengeAge(max_age) => {
+                let solved_at = outcome
+                    .challenge_time()
+                    .ok_or(TurnstileError::ChallengeExpired)?;
+                // a timestamp slightly in the future (clock skew) counts as fresh
+                let age = std::time::SystemTime::now()
+                    .duration_since(solved_at)
+                    .unwrap_or_default();
+                if age > *max_age {
+                    Err(TurnstileError::ChallengeExpired)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn check_hostname_in(
+    allowed: &[String],
+    outcome: &SiteVerifyResponse,
+) -> Result<(), TurnstileError> {
+    let hostname = outcome.hostname.as_deref().unwrap_or_default();
+    if allowed.iter().any(|h| h.eq_ignore_ascii_case(hostname)) {
+        Ok(())
+    } else {
+        Err(TurnstileError::HostnameMismatch(hostname.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(json: &str) -> SiteVerifyResponse {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_default_policy_only_requires_success() {
+        let policy = SuccessPolicy::default();
+
+        assert!(policy
+            .evaluate(&outcome(r#"{"success": true, "action": "other"}"#))
+            .is_ok());
+        assert!(matches!(
+            policy.evaluate(&outcome(
+                r#"{"success": false, "error-codes": ["invalid-input-response"]}"#
+            )),
+            Err(TurnstileError::VerificationFailed(codes)) if codes == ["invalid-input-response"]
+        ));
+    }
+
+    #[test]
+    fn test_policy_reports_first_failed_check() {
+        let policy = SuccessPolicy::default()
+            .require(SuccessCheck::Action("login".to_string()))
+            .require(SuccessCheck::Cdata("session-1".to_string()));
+
+        let both_wrong = outcome(r#"{"success": true, "action": "signup", "cdata": "x"}"#);
+        assert!(matches!(
+            policy.evaluate(&both_wrong),
+            Err(TurnstileError::ActionMismatch(action)) if action == "signup"
+        ));
+
+        let cdata_wrong = outcome(r#"{"success": true, "action": "login", "cdata": "x"}"#);
+        assert!(matches!(
+            policy.evaluate(&cdata_wrong),
+            Err(TurnstileError::CdataMismatch)
+        ));
+
+        let ok = outcome(r#"{"success": true, "action": "login", "cdata": "session-1"}"#);
+        assert!(policy.evaluate(&ok).is_ok());
+    }
+
+    #[test]
+    fn test_policy_no_error_codes_and_hostname() {
+        let policy = SuccessPolicy::default()
+            .require(SuccessCheck::NoErrorCodes)
+            .require(SuccessCheck::Hostname(vec!["example.com".to_string()]));
+
+        assert!(matches!(
+            policy.evaluate(&outcome(
+                r#"{"success": true, "error-codes": ["internal-error"]}"#
+            )),
+            Err(TurnstileError::VerificationFailed(_))
+        ));
+        assert!(matches!(
+            policy.evaluate(&outcome(r#"{"success": true, "hostname": "evil.com"}"#)),
+            Err(TurnstileError::HostnameMismatch(hostname)) if hostname == "evil.com"
+        ));
+        assert!(policy
+            .evaluate(&outcome(r#"{"success": true, "hostname": "Example.com"}"#))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_policy_min_score() {
+        let policy = SuccessPolicy::default().require(SuccessCheck::MinScore(0.5));
+
+        assert!(matches!(
+            policy.evaluate(&outcome(r#"{"success": true, "score": 0.2}"#)),
+            Err(TurnstileError::ScoreTooLow(score)) if score == 0.2
+        ));
+        assert!(policy
+            .evaluate(&outcome(r#"{"success": true, "score": 0.5}"#))
+            .is_ok());
+        // standard responses carry no score
+        assert!(policy.evaluate(&outcome(r#"{"success": true}"#)).is_ok());
+    }
+
+    #[test]
+    fn test_custom_policy() {
+        let policy =
+            SuccessPolicy::custom(
+                |outcome| match (outcome.success, outcome.action.as_deref()) {
+                    (true, Some("login" | "signup")) => Ok(()),
+                    (true, action) => Err(TurnstileError::ActionMismatch(
+                        action.unwrap_or_default().to_owned(),
+                    )),
+                    (false, _) => Err(TurnstileError::VerificationFailed(
+                        outcome.error_codes.clone(),
+                    )),
+                },
+            );
+
+        assert!(policy
+            .evaluate(&outcome(r#"{"success": true, "action": "signup"}"#))
+            .is_ok());
+        assert!(matches!(
+            policy.evaluate(&outcome(r#"{"success": true, "action": "other"}"#)),
+            Err(TurnstileError::ActionMismatch(action)) if action == "other"
+        ));
+        assert!(matches!(
+            policy.evaluate(&outcome(r#"{"success": false}"#)),
+            Err(TurnstileError::VerificationFailed(_))
+        ));
+        assert_eq!(
+            format!("{:?}", policy),
+            "SuccessPolicy { checks: [Custom(CustomCheck(..))] }"
+        );
+    }
+}
@@ -1,12 +1,149 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::Duration,
+};
 
 use once_cell::sync::Lazy;
 
-pub static REQWEST_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
-    reqwest::Client::builder()
-        .timeout(Duration::from_secs(5))
-        .connect_timeout(Duration::from_secs(5))
-        .pool_idle_timeout(Duration::from_secs(5))
-        .build()
-        .expect("Failed to build reqwest client")
-});
+use crate::TurnstileConfig;
+
+/// Shared client used when a config doesn't carry its own.
+pub static REQWEST_CLIENT: Lazy<reqwest::Client> =
+    Lazy::new(|| build_client(Some(SHARED_TIMEOUT_SECS)));
+
+const SHARED_TIMEOUT_SECS: u64 = 5;
+
+/// Shared clients for configs without their own but with a
+/// `connect_timeout_secs`, one per connect timeout: reqwest can't set it
+/// per request.
+static CONNECT_TIMEOUT_CLIENTS: Lazy<Mutex<HashMap<Duration, reqwest::Client>>> =
+    Lazy::new(Default::default);
+
+/// Adjusts a client builder before the client is built; see
+/// [`TurnstileConfig::customize_client`](crate::TurnstileConfig::customize_client).
+pub type ClientCustomizer =
+    Arc<dyn Fn(reqwest::ClientBuilder) -> reqwest::ClientBuilder + Send + Sync>;
+
+/// A client that can be swapped while the middleware runs, e.g. to pick up
+/// rotated TLS certificates or a new proxy without a restart; see
+/// [`TurnstileConfig::rotating_client`](crate::TurnstileConfig::rotating_client).
+///
+/// Clones share the same slot. Each siteverify call uses the client current
+/// when it starts; calls already in flight finish on the old one.
+#[derive(Clone, Debug)]
+pub struct RotatingClient {
+    current: Arc<RwLock<reqwest::Client>>,
+}
+
+impl RotatingClient {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(client)),
+        }
+    }
+
+    /// Replaces the client for all subsequent siteverify calls.
+    pub fn set_client(&self, client: reqwest::Client) {
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = client;
+    }
+
+    /// The current client; cheap, clients are reference-counted.
+    pub fn client(&self) -> reqwest::Client {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// Builds a client whose request and connect timeouts follow `timeout_secs`.
+/// `None` leaves the client without a timeout.
+///
+/// gzip and brotli decoding are enabled, so a gateway that compresses the
+/// siteverify response doesn't break JSON decoding.
+///
+/// Idle connections are kept for 90 seconds with TCP keep-alive, so bursty
+/// traffic reuses them instead of reconnecting, and HTTP/2 connections use
+/// an adaptive flow-control window. Anything more specific, such as
+/// `http2_prior_knowledge` or `pool_max_idle_per_host`, is up to
+/// [`build_client_with`].
+pub fn build_client(timeout_secs: Option<u64>) -> reqwest::Client {
+    build_client_with(timeout_secs, None)
+}
+
+/// [`build_client`], with `customize` applied on top of the defaults.
+///
+/// # Panics
+///
+/// If the customized builder fails to build, e.g. over an invalid TLS setup.
+pub fn build_client_with(
+    timeout_secs: Option<u64>,
+    customize: Option<&ClientCustomizer>,
+) -> reqwest::Client {
+    let timeout = timeout_secs.map(Duration::from_secs);
+    build(timeout, timeout, customize)
+}
+
+/// The total and connect timeouts of the client [`Turnstile::new`] builds
+/// for `config`.
+///
+/// [`Turnstile::new`]: crate::Turnstile::new
+pub(crate) fn config_timeouts(config: &TurnstileConfig) -> (Option<Duration>, Option<Duration>) {
+    let connect_secs = config.connect_timeout_secs.or(config.timeout_secs);
+    (
+        config.timeout_secs.map(Duration::from_secs),
+        connect_secs.map(Duration::from_secs),
+    )
+}
+
+/// The client siteverify calls use when `config` carries none, i.e. for the
+/// verify functions called directly: [`REQWEST_CLIENT`], or a shared client
+/// with the configured `connect_timeout_secs`.
+pub(crate) fn shared_client(config: &TurnstileConfig) -> reqwest::Client {
+    let Some(secs) = config.connect_timeout_secs else {
+        return REQWEST_CLIENT.clone();
+    };
+    CONNECT_TIMEOUT_CLIENTS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(Duration::from_secs(secs))
+        .or_insert_with_key(|&connect_timeout| {
+            let timeout = Duration::from_secs(SHARED_TIMEOUT_SECS);
+            build(Some(timeout), Some(connect_timeout), None)
+        })
+        .clone()
+}
+
+/// The client [`Turnstile::new`] builds when `config` has none.
+///
+/// [`Turnstile::new`]: crate::Turnstile::new
+pub(crate) fn build_config_client(config: &TurnstileConfig) -> reqwest::Client {
+    let (timeout, connect_timeout) = config_timeouts(config);
+    build(timeout, connect_timeout, config.customize_client.as_ref())
+}
+
+fn build(
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    customize: Option<&ClientCustomizer>,
+) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_keepalive(Duration::from_secs(60))
+        .http2_adaptive_window(true)
+        .gzip(true)
+        .brotli(true);
+
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+
+    if let Some(customize) = customize {
+        builder = customize(builder);
+    }
+    builder.build().expect("Failed to build reqwest client")
+}
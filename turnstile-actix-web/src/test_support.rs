@@ -0,0 +1,250 @@
+use std::{
+    io::Read,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    test::TestRequest,
+    web, App, HttpResponse, HttpServer,
+};
+
+/// Serves the apps built by `app` on a local port in the background.
+/// Returns the siteverify URL on it.
+pub fn serve<F, T, B>(app: F) -> String
+where
+    F: Fn() -> App<T> + Send + Clone + 'static,
+    T: ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind stub listener");
+    let addr = listener.local_addr().unwrap();
+
+    let server = HttpServer::new(app)
+        .workers(1)
+        .disable_signals()
+        .listen(listener)
+        .expect("Failed to start stub server")
+        .run();
+    actix_web::rt::spawn(server);
+
+    format!("http://{}/turnstile/v0/siteverify", addr)
+}
+
+/// Starts a local stand-in for the siteverify endpoint that answers every
+/// request with the given status, headers and body. Returns its URL.
+pub fn spawn_siteverify_stub(
+    status: StatusCode,
+    headers: Vec<(&'static str, &'static str)>,
+    body: &'static str,
+) -> String {
+    spawn_delayed_siteverify_stub(Duration::ZERO, status, headers, body)
+}
+
+/// Same as [`spawn_siteverify_stub`], but waits `delay` before answering.
+pub fn spawn_delayed_siteverify_stub(
+    delay: Duration,
+    status: StatusCode,
+    headers: Vec<(&'static str, &'static str)>,
+    body: &'static str,
+) -> String {
+    serve(move || {
+        let headers = headers.clone();
+        App::new().default_service(web::to(move || {
+            let headers = headers.clone();
+            async move {
+                actix_web::rt::time::sleep(delay).await;
+                let mut res = HttpResponse::build(status);
+                // headers may override the content type
+                res.content_type("application/json");
+                for header in headers {
+                    res.insert_header(header);
+                }
+                res.body(body)
+            }
+        }))
+    })
+}
+
+/// A siteverify stand-in that compresses its answer with whatever encoding
+/// the client offers, like a compressing gateway in front of Cloudflare.
+pub fn spawn_compressed_siteverify_stub(body: &'static str) -> String {
+    serve(move || {
+        App::new()
+            .wrap(actix_web::middleware::Compress::default())
+            .default_service(web::to(move || async move {
+                HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(body)
+            }))
+    })
+}
+
+/// A siteverify stand-in answering requests with `responses` in order,
+/// repeating the last one once the list is exhausted. Bodies starting with
+/// `<` are sent as `text/html`, others as `application/json`.
+pub fn spawn_sequenced_siteverify_stub(responses: Vec<(StatusCode, &'static str)>) -> String {
+    let served = Arc::new(Mutex::new(0usize));
+
+    serve(move || {
+        let responses = responses.clone();
+        let served = served.clone();
+        App::new().default_service(web::to(move || {
+            let mut served = served.lock().unwrap();
+            let (status, body) = responses[(*served).min(responses.len() - 1)];
+            *served += 1;
+            let content_type = if body.starts_with('<') {
+                "text/html; charset=UTF-8"
+            } else {
+                "application/json"
+            };
+            async move {
+                HttpResponse::build(status)
+                    .content_type(content_type)
+                    .body(body)
+            }
+        }))
+    })
+}
+
+/// Received siteverify request bodies, in arrival order.
+pub type RecordedRequests = Arc<Mutex<Vec<serde_json::Value>>>;
+
+/// A `200 OK` siteverify stand-in answering `body` that also records the
+/// JSON body of every request it receives.
+pub fn spawn_recording_siteverify_stub(body: &'static str) -> (String, RecordedRequests) {
+    spawn_answering_siteverify_stub(move |_| body)
+}
+
+/// A siteverify stand-in that only accepts `secret`, recording request bodies
+/// like [`spawn_recording_siteverify_stub`].
+pub fn spawn_secret_checking_siteverify_stub(secret: &'static str) -> (String, RecordedRequests) {
+    spawn_answering_siteverify_stub(move |req| {
+        if req["secret"] == secret {
+            r#"{"success": true}"#
+        } else {
+            r#"{"success": false, "error-codes": ["invalid-input-response"]}"#
+        }
+    })
+}
+
+/// A `200 OK` siteverify stand-in answering each request with the body
+/// `answer` picks for it, recording request bodies.
+fn spawn_answering_siteverify_stub(
+    answer: impl Fn(&serde_json::Value) -> &'static str + Send + Clone + 'static,
+) -> (String, RecordedRequests) {
+    let requests = RecordedRequests::default();
+
+    let recorded = requests.clone();
+    let url = serve(move || {
+        let recorded = recorded.clone();
+        let answer = answer.clone();
+        App::new().default_service(web::to(move |req: web::Json<serde_json::Value>| {
+            let body = answer(&req);
+            recorded.lock().unwrap().push(req.into_inner());
+            async move {
+                HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(body)
+            }
+        }))
+    });
+
+    (url, requests)
+}
+
+/// A siteverify stand-in that only accepts requests carrying header `name`
+/// with `value`.
+pub fn spawn_header_checking_siteverify_stub(name: &'static str, value: &'static str) -> String {
+    serve(move || {
+        App::new().default_service(web::to(move |req: actix_web::HttpRequest| {
+            let success = req.headers().get(name).is_some_and(|v| v == value);
+            async move { HttpResponse::Ok().json(serde_json::json!({ "success": success })) }
+        }))
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StubEvent {
+    /// A siteverify request arrived.
+    Started,
+    /// The client closed the connection without waiting for an answer.
+    Closed,
+}
+
+/// A siteverify stand-in that accepts one request and never answers,
+/// reporting when the request arrives and when the client hangs up.
+pub fn spawn_hanging_siteverify_stub() -> (String, mpsc::Receiver<StubEvent>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind stub listener");
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        let mut buf = [0; 4096];
+        let mut started = false;
+        // keep reading until EOF, which only comes from the client hanging up
+        while let Ok(n) = stream.read(&mut buf) {
+            if n == 0 {
+                let _ = tx.send(StubEvent::Closed);
+                return;
+            }
+            if !started {
+                started = true;
+                let _ = tx.send(StubEvent::Started);
+            }
+        }
+    });
+
+    (format!("http://{}/turnstile/v0/siteverify", addr), rx)
+}
+
+/// A siteverify address that can't be connected to: its listener never
+/// accepts and its one-slot backlog is taken, so the kernel drops further
+/// connection attempts and they hang until the client gives up, like an
+/// unreachable host.
+pub struct UnconnectableStub {
+    pub url: String,
+    _listener: socket2::Socket,
+    _queued: TcpStream,
+}
+
+pub fn spawn_unconnectable_siteverify_stub() -> UnconnectableStub {
+    use socket2::{Domain, Socket, Type};
+
+    let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    listener.bind(&addr.into()).unwrap();
+    listener.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap().as_socket().unwrap();
+    let queued = TcpStream::connect(addr).unwrap();
+
+    UnconnectableStub {
+        url: format!("http://{}/turnstile/v0/siteverify", addr),
+        _listener: listener,
+        _queued: queued,
+    }
+}
+
+/// A request to `/` carrying `token` in the `cf-turnstile-response` header
+/// and a peer address, ready for further customization.
+pub fn token_request(token: &str) -> TestRequest {
+    TestRequest::get()
+        .uri("/")
+        .insert_header(("cf-turnstile-response", token))
+        .peer_addr("192.168.1.1:12345".parse().unwrap())
+}
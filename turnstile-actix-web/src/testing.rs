@@ -0,0 +1,216 @@
+//! Cloudflare's Turnstile test keys, for deterministic tests.
+//!
+//! The sitekey decides how the widget behaves in the browser; the secret
+//! decides what siteverify answers. Test sitekeys only ever produce
+//! [`DUMMY_TOKEN`], and test secrets only accept that token, so pair them
+//! freely. For end-to-end tests:
+//!
+//! | Scenario | Sitekey | Secret |
+//! |---|---|---|
+//! | Everything passes | [`SITEKEY_ALWAYS_PASSES`] | [`SECRET_ALWAYS_PASSES`] |
+//! | Widget blocks the visitor | [`SITEKEY_ALWAYS_BLOCKS`] | any |
+//! | Visitor must click | [`SITEKEY_FORCE_INTERACTIVE`] | [`SECRET_ALWAYS_PASSES`] |
+//! | Widget passes, server rejects | [`SITEKEY_ALWAYS_PASSES`] | [`SECRET_ALWAYS_FAILS`] |
+//! | Replayed token | [`SITEKEY_ALWAYS_PASSES`] | [`SECRET_TOKEN_SPENT`] |
+//!
+//! Test keys work on any hostname, including `localhost`. Set
+//! `reject_test_secrets` in production configs so a test secret shipped by
+//! mistake fails [`check`](crate::TurnstileConfig::check) and
+//! [`validate`](crate::TurnstileConfig::validate).
+
+/// Visible widget that always passes.
+pub const SITEKEY_ALWAYS_PASSES: &str = "1x00000000000000000000AA";
+/// Visible widget that always blocks.
+pub const SITEKEY_ALWAYS_BLOCKS: &str = "2x00000000000000000000AB";
+/// Invisible widget that always passes.
+pub const SITEKEY_INVISIBLE_ALWAYS_PASSES: &str = "1x00000000000000000000BB";
+/// Invisible widget that always blocks.
+pub const SITEKEY_INVISIBLE_ALWAYS_BLOCKS: &str = "2x00000000000000000000BB";
+/// Visible widget that always shows an interactive challenge.
+pub const SITEKEY_FORCE_INTERACTIVE: &str = "3x00000000000000000000FF";
+
+/// Secret for which siteverify always succeeds.
+pub const SECRET_ALWAYS_PASSES: &str = "1x0000000000000000000000000000000AA";
+/// Secret for which siteverify always fails with `invalid-input-response`.
+pub const SECRET_ALWAYS_FAILS: &str = "2x0000000000000000000000000000000AA";
+/// Secret for which siteverify always fails with `timeout-or-duplicate`.
+pub const SECRET_TOKEN_SPENT: &str = "3x0000000000000000000000000000000AA";
+
+/// Every known test secret.
+pub(crate) const TEST_SECRETS: &[&str] = &[
+    SECRET_ALWAYS_PASSES,
+    SECRET_ALWAYS_FAILS,
+    SECRET_TOKEN_SPENT,
+];
+
+/// Whether `secret` is one of Cloudflare's test secrets.
+pub fn is_test_secret(secret: &str) -> bool {
+    TEST_SECRETS.contains(&secret.trim())
+}
+
+/// The token produced by test sitekeys.
+pub const DUMMY_TOKEN: &str = "XXXX.DUMMY.TOKEN.XXXX";
+
+/// An in-memory siteverify stand-in, to set as
+/// [`TurnstileConfig::http_backend`](crate::TurnstileConfig::http_backend),
+/// for deterministic tests of timeouts, retries and `on_network_error`.
+///
+/// Every call waits [`delay`](Self::delay), then fails like a dropped
+/// connection for the first [`fail_times`](Self::fail_times) calls;
+/// afterwards the token passes, or is rejected with the codes given to
+/// [`reject_with`](Self::reject_with). Clones share the call count.
+#[cfg(feature = "mock")]
+#[derive(Clone, Debug, Default)]
+pub struct MockVerifier {
+    delay: std::time::Duration,
+    failures: usize,
+    error_codes: Vec<String>,
+    calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(feature = "mock")]
+impl MockVerifier {
+    /// A verifier that accepts every token right away.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits `delay` before answering each call.
+    pub fn delay(mut self, delay: std::time::Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Fails the first `times` calls with a connection error.
+    pub fn fail_times(mut self, times: usize) -> Self {
+        self.failures = times;
+        self
+    }
+
+    /// Answers `success: false` with `codes` instead of accepting the token.
+    pub fn reject_with<I, S>(mut self, codes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.error_codes = codes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Calls received so far, including failed ones.
+    pub fn calls(&self) -> usize {
+        self.calls.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(feature = "mock")]
+impl crate::backend::HttpBackend for MockVerifier {
+    fn post_json<'a>(
+        &'a self,
+        _url: &'a str,
+        _body: &'a serde_json::Value,
+    ) -> futures_util::future::BoxFuture<
+        'a,
+        Result<crate::backend::BackendResponse, crate::backend::BackendError>,
+    > {
+        let call = self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Box::pin(async move {
+            if !self.delay.is_zero() {
+                actix_web::rt::time::sleep(self.delay).await;
+            }
+            if call < self.failures {
+                return Err("mock connection failure".into());
+            }
+            let body = serde_json::json!({
+                "success": self.error_codes.is_empty(),
+                "error-codes": self.error_codes,
+            });
+            Ok(crate::backend::BackendResponse {
+                status: 200,
+                headers: vec![("content-type".to_string(), "application/json".to_string())],
+                body: body.to_string(),
+            })
+        })
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+    use crate::{error::TurnstileError, test_support, Turnstile, TurnstileConfig};
+
+    fn mock_config(mock: &MockVerifier) -> TurnstileConfig {
+        let mut config = TurnstileConfig::new(SECRET_ALWAYS_PASSES);
+        config.http_backend = Some(Arc::new(mock.clone()));
+        config.retry_backoff = Duration::ZERO;
+        config
+    }
+
+    #[actix_web::test]
+    async fn test_mock_retries_until_success() {
+        let mock = MockVerifier::new().fail_times(2);
+        let mut config = mock_config(&mock);
+        config.retries = 2;
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test_support::token_request(DUMMY_TOKEN).to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(mock.calls(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_mock_failures_and_rejections() {
+        let mock = MockVerifier::new().fail_times(1);
+        let mut config = mock_config(&mock);
+        config.retries = 0;
+        let turnstile = Turnstile::new(config);
+        assert!(matches!(
+            turnstile.verify_with(DUMMY_TOKEN, None).await,
+            Err(TurnstileError::BackendError(_))
+        ));
+        assert!(turnstile.verify_with(DUMMY_TOKEN, None).await.is_ok());
+
+        let mock = MockVerifier::new().reject_with(["timeout-or-duplicate"]);
+        let turnstile = Turnstile::new(mock_config(&mock));
+        assert!(matches!(
+            turnstile.verify_with(DUMMY_TOKEN, None).await,
+            Err(TurnstileError::VerificationFailed(codes)) if codes == ["timeout-or-duplicate"]
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_mock_delay_hits_timeout() {
+        let mock = MockVerifier::new().delay(Duration::from_millis(200));
+        let mut config = mock_config(&mock);
+        config.deadline_header = Some("x-deadline".to_string());
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Turnstile::new(config))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+        let deadline = std::time::SystemTime::now() + Duration::from_millis(50);
+        let deadline = deadline
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let req = test_support::token_request(DUMMY_TOKEN)
+            .insert_header(("x-deadline", deadline.to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
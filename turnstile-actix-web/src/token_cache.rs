@@ -0,0 +1,222 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{BuildHasher, RandomState},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::future::BoxFuture;
+
+/// Shared record of accepted tokens, for rejecting replays across every
+/// instance of a cluster (e.g. backed by Redis), where a [`TokenCache`]
+/// only sees its own process.
+///
+/// Tokens are identified by [`token_hash`]. Before calling siteverify the
+/// middleware claims the token with
+/// [`check_and_record`](Self::check_and_record), and gives the claim back
+/// with [`forget`](Self::forget) if the token isn't accepted, so only
+/// accepted tokens stay recorded. A request cancelled mid-verification
+/// leaves its claim in place until it expires. [`TokenCache`] implements it
+/// in memory.
+pub trait TokenStore: Send + Sync {
+    /// Records `token_hash` for `ttl` unless it's already recorded and
+    /// unexpired, returning whether it was recorded. This must be a single
+    /// atomic insert-if-absent (e.g. Redis `SET key 1 NX PX ttl`), so two
+    /// concurrent requests with one token can't both pass.
+    fn check_and_record<'a>(&'a self, token_hash: &'a str, ttl: Duration) -> BoxFuture<'a, bool>;
+
+    /// Removes `token_hash`.
+    fn forget<'a>(&'a self, token_hash: &'a str) -> BoxFuture<'a, ()>;
+}
+
+/// The key a token is stored under in a [`TokenStore`]: its SHA-256 in
+/// lowercase hex, the same on every instance.
+pub fn token_hash(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Bounded cache of tokens that already passed verification, used to reject
+/// replays without a siteverify round trip.
+///
+/// Clones share the same storage, so a single cache can back the middleware
+/// instances of every worker. Tokens are stored as keyed hashes; once the
+/// cache is full the oldest entry is evicted. Entries recorded through
+/// [`TokenStore`] also expire after their `ttl`; expired entries keep their
+/// slot (and count towards [`len`](Self::len)) until evicted or recorded
+/// again.
+#[derive(Clone)]
+pub struct TokenCache {
+    inner: Arc<Mutex<Inner>>,
+    hasher: RandomState,
+}
+
+impl std::fmt::Debug for TokenCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.lock();
+        f.debug_struct("TokenCache")
+            .field("capacity", &inner.capacity)
+            .field("len", &inner.order.len())
+            .finish()
+    }
+}
+
+struct Inner {
+    capacity: usize,
+    order: VecDeque<u64>,
+    /// Expiry of each entry; `None` for entries kept until evicted.
+    seen: HashMap<u64, Option<Instant>>,
+}
+
+impl Inner {
+    fn contains(&self, key: u64) -> bool {
+        self.seen
+            .get(&key)
+            .is_some_and(|expires| expires.is_none_or(|expires| expires > Instant::now()))
+    }
+
+    /// Records `key` unless it is present, evicting the oldest entry if the
+    /// cache is full; `false` if it was present.
+    fn insert(&mut self, key: u64, expires: Option<Instant>) -> bool {
+        if self.contains(key) {
+            return false;
+        }
+        if self.capacity == 0 {
+            return true;
+        }
+        // an expired entry is renewed in place
+        if self.seen.insert(key, expires).is_none() {
+            self.order.push_back(key);
+            if self.order.len() > self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.seen.remove(&oldest);
+                }
+            }
+        }
+        true
+    }
+}
+
+impl TokenCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                order: VecDeque::with_capacity(capacity),
+                seen: HashMap::with_capacity(capacity),
+            })),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Whether `token` has already been recorded.
+    pub fn contains(&self, token: &str) -> bool {
+        let key = self.hasher.hash_one(token);
+        self.lock().contains(key)
+    }
+
+    /// Records `token`, evicting the oldest entry if the cache is full.
+    pub fn insert(&self, token: &str) {
+        let key = self.hasher.hash_one(token);
+        self.lock().insert(key, None);
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        inner.order.clear();
+        inner.seen.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // the cache holds no invariants a panicking holder could break
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Atomic under the cache's lock. Entries expire after `ttl`, or earlier
+/// when evicted by capacity.
+impl TokenStore for TokenCache {
+    fn check_and_record<'a>(&'a self, token_hash: &'a str, ttl: Duration) -> BoxFuture<'a, bool> {
+        let key = self.hasher.hash_one(token_hash);
+        let recorded = self.lock().insert(key, Some(Instant::now() + ttl));
+        Box::pin(std::future::ready(recorded))
+    }
+
+    fn forget<'a>(&'a self, token_hash: &'a str) -> BoxFuture<'a, ()> {
+        let key = self.hasher.hash_one(token_hash);
+        let mut inner = self.lock();
+        if inner.seen.remove(&key).is_some() {
+            inner.order.retain(|&entry| entry != key);
+        }
+        Box::pin(std::future::ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_cache_evicts_oldest() {
+        let cache = TokenCache::new(2);
+        cache.insert("a");
+        cache.insert("b");
+        cache.insert("c");
+
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("b"));
+        assert!(cache.contains("c"));
+    }
+
+    #[test]
+    fn test_token_cache_clear_is_shared_between_clones() {
+        let cache = TokenCache::new(8);
+        let handle = cache.clone();
+        cache.insert("a");
+        cache.insert("a");
+        assert_eq!(handle.len(), 1);
+
+        handle.clear();
+        assert!(cache.is_empty());
+        assert!(!cache.contains("a"));
+    }
+
+    #[actix_web::test]
+    async fn test_token_cache_as_store() {
+        let cache = TokenCache::new(8);
+        let ttl = Duration::from_millis(50);
+        assert!(cache.check_and_record("a", ttl).await);
+        assert!(!cache.check_and_record("a", ttl).await);
+
+        cache.forget("a").await;
+        assert!(cache.is_empty());
+        assert!(cache.check_and_record("a", ttl).await);
+
+        // expired entries can be recorded again
+        actix_web::rt::time::sleep(Duration::from_millis(80)).await;
+        assert!(!cache.contains("a"));
+        assert!(cache.check_and_record("a", ttl).await);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_token_hash() {
+        assert_eq!(
+            token_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
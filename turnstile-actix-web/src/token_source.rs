@@ -0,0 +1,388 @@
+use actix_web::{cookie::Cookie, dev::ServiceRequest, http::header::COOKIE, HttpMessage};
+
+use crate::{body, error::TurnstileError, multipart, TurnstileConfig};
+
+/// Where the middleware looks for the Turnstile token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenSource {
+    /// A request header, `cf-turnstile-response` by default.
+    Header(String),
+    /// A string field of a JSON request body, located by a dotted path
+    /// (`captcha.token`) or a JSON pointer (`/captcha/token`). The body is
+    /// buffered up to `body_limit` and handed on to the handler intact. An
+    /// empty body, or one declared as something other than JSON, counts as
+    /// absent; a JSON body that doesn't parse is rejected with
+    /// [`TurnstileError::InvalidTokenFormat`].
+    JsonField(String),
+    /// A text field of a `multipart/form-data` body, e.g. next to a file
+    /// upload. The body is only read up to the end of that field — at most
+    /// `body_limit` bytes, beyond which the request is rejected with `413
+    /// Payload Too Large` — and the rest is streamed to the handler as
+    /// usual, which receives the complete body. Place the widget before file
+    /// inputs so the token is sent first. Bodies that aren't multipart count
+    /// as absent.
+    MultipartField(String),
+    /// A cookie, percent-decoded. Like a header, it must be sent at most
+    /// once.
+    Cookie(String),
+    /// A query parameter, for clients that can't set headers or send a body,
+    /// such as `EventSource` or WebSocket handshakes. Like a header, it must
+    /// be sent at most once. Query strings tend to end up in access logs,
+    /// though the token is single-use.
+    Query(String),
+}
+
+impl Default for TokenSource {
+    fn default() -> Self {
+        TokenSource::Header("cf-turnstile-response".to_string())
+    }
+}
+
+impl TokenSource {
+    /// Reads the token from this source; `Ok(None)` if the source is absent.
+    pub(crate) async fn extract(
+        &self,
+        req: &mut ServiceRequest,
+        body_limit: usize,
+    ) -> Result<Option<String>, TurnstileError> {
+        match self {
+            TokenSource::Header(name) => {
+                header_token(req.headers().get_all(name).map(|value| value.to_str().ok()))
+            }
+            TokenSource::JsonField(path) => {
+                // bodyless and non-JSON requests leave the later sources to try
+                let declared_json = match req.mime_type() {
+                    Ok(Some(mime)) => {
+                        mime.subtype() == "json" || mime.suffix().is_some_and(|s| s == "json")
+                    }
+                    Ok(None) => true,
+                    Err(_) => false,
+                };
+                if !declared_json {
+                    return Ok(None);
+                }
+                let body = body::buffer_body(req, body_limit).await?;
+                if body.is_empty() {
+                    return Ok(None);
+                }
+                let json = serde_json::from_slice::<serde_json::Value>(&body)
+                    .map_err(|_| TurnstileError::InvalidTokenFormat)?;
+                match json.pointer(&json_pointer(path)) {
+                    None => Ok(None),
+                    Some(serde_json::Value::String(token)) => Ok(Some(token.clone())),
+                    Some(_) => Err(TurnstileError::InvalidTokenFormat),
+                }
+            }
+            TokenSource::MultipartField(name) => multipart::find_field(req, name, body_limit).await,
+            TokenSource::Cookie(name) => cookie_token(
+                req.headers().get_all(COOKIE).map(|value| value.as_bytes()),
+                name,
+            ),
+            TokenSource::Query(name) => query_token(req.query_string(), name),
+        }
+    }
+}
+
+/// Reads the token from the first of `config.token_sources` that carries one.
+///
+/// With `require_token_sources_match`, every source is read and all tokens
+/// found must be identical.
+pub(crate) async fn extract_token(
+    req: &mut ServiceRequest,
+    config: &TurnstileConfig,
+) -> Result<String, TurnstileError> {
+    let mut pick = TokenPick::new(config);
+    for source in &config.token_sources {
+        if pick.offer(source.extract(req, config.body_limit).await?)? {
+            break;
+        }
+    }
+    if let Some(name) = pick.fallback() {
+        pick.offer(query_token(req.query_string(), name)?)?;
+    }
+    pick.finish()
+}
+
+/// The token picked from what `config.token_sources` carry, offered source
+/// by source in order, so the middleware and [`RequestParts`] apply
+/// `require_token_sources_match` and `token_query_fallback` alike.
+///
+/// [`RequestParts`]: crate::parts::RequestParts
+pub(crate) struct TokenPick<'a> {
+    config: &'a TurnstileConfig,
+    found: Option<String>,
+}
+
+impl<'a> TokenPick<'a> {
+    pub(crate) fn new(config: &'a TurnstileConfig) -> Self {
+        Self {
+            config,
+            found: None,
+        }
+    }
+
+    /// Takes what the next source carries; `true` once the pick is settled
+    /// and the remaining sources needn't be read.
+    pub(crate) fn offer(&mut self, token: Option<String>) -> Result<bool, TurnstileError> {
+        let Some(token) = token else {
+            return Ok(false);
+        };
+        match &self.found {
+            Some(first) if *first != token => return Err(TurnstileError::InvalidTokenFormat),
+            Some(_) => {}
+            None => self.found = Some(token),
+        }
+        Ok(!self.config.require_token_sources_match)
+    }
+
+    /// The `token_query_fallback` parameter, if no source carried a token.
+    pub(crate) fn fallback(&self) -> Option<&'a str> {
+        match self.found {
+            None => self.config.token_query_fallback.as_deref(),
+            Some(_) => None,
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<String, TurnstileError> {
+        self.found.ok_or(TurnstileError::TokenNotFound)
+    }
+}
+
+/// The token in a header, given its values as strings (`None` for a value
+/// that isn't visible ASCII).
+///
+/// A request carrying the header more than once is rejected with
+/// [`TurnstileError::InvalidTokenFormat`] rather than picking one of the
+/// values, so proxies and the middleware can't disagree on which token was
+/// verified.
+pub(crate) fn header_token<'v>(
+    mut values: impl Iterator<Item = Option<&'v str>>,
+) -> Result<Option<String>, TurnstileError> {
+    let Some(value) = values.next() else {
+        return Ok(None);
+    };
+    if values.next().is_some() {
+        return Err(TurnstileError::InvalidTokenFormat);
+    }
+
+    value
+        .map(|token| Some(token.to_owned()))
+        .ok_or(TurnstileError::InvalidTokenFormat)
+}
+
+/// The cookie `name` among the `Cookie` header values, percent-decoded and
+/// parsed as actix does.
+pub(crate) fn cookie_token<'v>(
+    headers: impl Iterator<Item = &'v [u8]>,
+    name: &str,
+) -> Result<Option<String>, TurnstileError> {
+    let mut found = None;
+    for header in headers {
+        let header = std::str::from_utf8(header).map_err(|_| TurnstileError::InvalidTokenFormat)?;
+        for pair in header
+            .split(';')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let cookie =
+                Cookie::parse_encoded(pair).map_err(|_| TurnstileError::InvalidTokenFormat)?;
+            if cookie.name() != name {
+                continue;
+            }
+            if found.is_some() {
+                return Err(TurnstileError::InvalidTokenFormat);
+            }
+            found = Some(cookie.value().to_owned());
+        }
+    }
+    Ok(found)
+}
+
+pub(crate) fn query_token(query: &str, name: &str) -> Result<Option<String>, TurnstileError> {
+    let params: Vec<(String, String)> =
+        serde_urlencoded::from_str(query).map_err(|_| TurnstileError::InvalidTokenFormat)?;
+    let mut values = params.into_iter().filter(|(key, _)| key == name);
+    let Some((_, token)) = values.next() else {
+        return Ok(None);
+    };
+    if values.next().is_some() {
+        return Err(TurnstileError::InvalidTokenFormat);
+    }
+    Ok(Some(token))
+}
+
+/// Turns a dotted path into a JSON pointer; pointers are passed through.
+fn json_pointer(path: &str) -> String {
+    if path.starts_with('/') {
+        return path.to_owned();
+    }
+
+    path.split('.')
+        .map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_json_pointer() {
+        assert_eq!(json_pointer("captcha.token"), "/captcha/token");
+        assert_eq!(json_pointer("items.0.token"), "/items/0/token");
+        assert_eq!(json_pointer("a/b.c"), "/a~1b/c");
+        assert_eq!(json_pointer("/captcha/token"), "/captcha/token");
+    }
+
+    #[actix_web::test]
+    async fn test_token_sources_must_match() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.token_sources = vec![
+            TokenSource::default(),
+            TokenSource::JsonField("token".to_string()),
+        ];
+        config.require_token_sources_match = true;
+
+        let request = |header: &'static str, body: &'static str| {
+            TestRequest::post()
+                .insert_header(("cf-turnstile-response", header))
+                .set_payload(body)
+                .to_srv_request()
+        };
+
+        let mut req = request("abc", r#"{"token": "abc"}"#);
+        assert_eq!(extract_token(&mut req, &config).await.unwrap(), "abc");
+
+        let mut req = request("abc", r#"{"token": "xyz"}"#);
+        assert!(matches!(
+            extract_token(&mut req, &config).await,
+            Err(TurnstileError::InvalidTokenFormat)
+        ));
+
+        // a single present source is enough
+        let mut req = request("abc", "{}");
+        assert_eq!(extract_token(&mut req, &config).await.unwrap(), "abc");
+
+        config.require_token_sources_match = false;
+        let mut req = request("abc", r#"{"token": "xyz"}"#);
+        assert_eq!(extract_token(&mut req, &config).await.unwrap(), "abc");
+    }
+
+    #[actix_web::test]
+    async fn test_json_field_extraction() {
+        async fn extract(body: &'static str) -> Result<Option<String>, TurnstileError> {
+            let mut req = TestRequest::post().set_payload(body).to_srv_request();
+            TokenSource::JsonField("captcha.token".to_string())
+                .extract(&mut req, 1024)
+                .await
+        }
+
+        assert_eq!(
+            extract(r#"{"captcha": {"token": "abc"}}"#).await.unwrap(),
+            Some("abc".to_string())
+        );
+        assert_eq!(extract(r#"{"captcha": {}}"#).await.unwrap(), None);
+        assert!(matches!(
+            extract(r#"{"captcha": {"token": 42}}"#).await,
+            Err(TurnstileError::InvalidTokenFormat)
+        ));
+        assert!(matches!(
+            extract("captcha=abc").await,
+            Err(TurnstileError::InvalidTokenFormat)
+        ));
+        assert_eq!(extract("").await.unwrap(), None);
+
+        let field = TokenSource::JsonField("captcha.token".to_string());
+        let mut form = TestRequest::post()
+            .insert_header(("content-type", "application/x-www-form-urlencoded"))
+            .set_payload("captcha=abc")
+            .to_srv_request();
+        assert_eq!(field.extract(&mut form, 1024).await.unwrap(), None);
+        let mut problem = TestRequest::post()
+            .insert_header(("content-type", "application/vnd.api+json"))
+            .set_payload("{not json")
+            .to_srv_request();
+        assert!(matches!(
+            field.extract(&mut problem, 1024).await,
+            Err(TurnstileError::InvalidTokenFormat)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_json_field_falls_through_without_body() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.token_sources = vec![
+            TokenSource::JsonField("token".to_string()),
+            TokenSource::default(),
+        ];
+        config.token_query_fallback = Some("cf-turnstile-response".to_string());
+
+        let mut req = TestRequest::get()
+            .insert_header(("cf-turnstile-response", "abc"))
+            .to_srv_request();
+        assert_eq!(extract_token(&mut req, &config).await.unwrap(), "abc");
+
+        let mut req = TestRequest::get()
+            .uri("/?cf-turnstile-response=xyz")
+            .to_srv_request();
+        assert_eq!(extract_token(&mut req, &config).await.unwrap(), "xyz");
+
+        let mut req = TestRequest::post()
+            .insert_header(("content-type", "text/plain"))
+            .insert_header(("cf-turnstile-response", "abc"))
+            .set_payload("hello")
+            .to_srv_request();
+        assert_eq!(extract_token(&mut req, &config).await.unwrap(), "abc");
+    }
+
+    #[actix_web::test]
+    async fn test_cookie_extraction() {
+        async fn extract(cookie: Option<&'static str>) -> Result<Option<String>, TurnstileError> {
+            let mut req = TestRequest::post();
+            if let Some(cookie) = cookie {
+                req = req.insert_header(("cookie", cookie));
+            }
+            TokenSource::Cookie("cf_token".to_string())
+                .extract(&mut req.to_srv_request(), 1024)
+                .await
+        }
+
+        assert_eq!(
+            extract(Some("session=1; cf_token=abc%2Bdef%3D%3D"))
+                .await
+                .unwrap(),
+            Some("abc+def==".to_string())
+        );
+        assert_eq!(extract(Some("session=1")).await.unwrap(), None);
+        assert_eq!(extract(None).await.unwrap(), None);
+        assert!(matches!(
+            extract(Some("cf_token=abc; cf_token=xyz")).await,
+            Err(TurnstileError::InvalidTokenFormat)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_query_extraction() {
+        async fn extract(uri: &'static str) -> Result<Option<String>, TurnstileError> {
+            let mut req = TestRequest::get().uri(uri).to_srv_request();
+            TokenSource::Query("cf-turnstile-response".to_string())
+                .extract(&mut req, 1024)
+                .await
+        }
+
+        assert_eq!(
+            extract("/events?channel=1&cf-turnstile-response=abc%2B%3D")
+                .await
+                .unwrap(),
+            Some("abc+=".to_string())
+        );
+        assert_eq!(extract("/events?channel=1").await.unwrap(), None);
+        assert_eq!(extract("/events").await.unwrap(), None);
+        assert!(matches!(
+            extract("/events?cf-turnstile-response=a&cf-turnstile-response=b").await,
+            Err(TurnstileError::InvalidTokenFormat)
+        ));
+    }
+}
@@ -1,25 +1,1105 @@
-use serde_json::{json, Value};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
-use crate::{reqwest_client::REQWEST_CLIENT, TurnstileConfig};
+use reqwest::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    StatusCode,
+};
+use serde::Deserialize;
+use serde_json::json;
 
+use crate::{
+    backend::HttpBackend,
+    error::{TurnstileError, TurnstileErrorCode, SECRET_ERROR_CODES},
+    reqwest_client, MissingSuccess, RetryJitter, TurnstileConfig,
+};
+
+/// Body of a siteverify response.
+///
+/// After a successful verification the middleware stores it in the request
+/// extensions, so handlers can take it as `web::ReqData<SiteVerifyResponse>`.
+///
+/// Decoding is strict about `success`: a body without a boolean `success`
+/// is a malformed response, not a failed verification, unless
+/// [`TurnstileConfig::treat_missing_success_as`] says otherwise. Any other
+/// field of an unexpected type is left unset (with a warning under the `log`
+/// feature) rather than failing the decode.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SiteVerifyResponse {
+    pub success: bool,
+    /// `error-codes`, or `error_codes` as some proxies rewrite it; see also
+    /// [`TurnstileConfig::error_codes_field`].
+    #[serde(rename = "error-codes", alias = "error_codes", default)]
+    pub error_codes: Vec<String>,
+    pub challenge_ts: Option<String>,
+    pub hostname: Option<String>,
+    pub action: Option<String>,
+    pub cdata: Option<String>,
+    /// Interaction score reported by Turnstile Enterprise; `None` for
+    /// standard responses.
+    #[serde(default)]
+    pub score: Option<f64>,
+    /// Extra details Cloudflare attaches to some responses; see
+    /// [`interactive`](Self::interactive).
+    #[serde(default)]
+    pub metadata: Option<ResponseMetadata>,
+    /// Siteverify response headers matching
+    /// [`TurnstileConfig::captured_response_headers`], keyed by lowercase name.
+    #[serde(skip)]
+    pub captured_headers: HashMap<String, String>,
+    /// Which secret produced this response: 0 for `secret_key`, `n` for the
+    /// `n`-th entry of `fallback_secret_keys`.
+    #[serde(skip)]
+    pub secret_index: usize,
+    /// `success` was set because all error codes are listed in
+    /// [`TurnstileConfig::treat_as_success_codes`]; Cloudflare rejected the
+    /// token.
+    #[serde(skip)]
+    pub success_overridden: bool,
+    /// The body as Cloudflare sent it, when
+    /// [`TurnstileConfig::retain_raw_response`] is set.
+    #[serde(skip)]
+    pub raw: Option<serde_json::Value>,
+    /// When this crate received the response (unlike `challenge_ts`, which
+    /// is when the visitor solved the challenge), so code further down a
+    /// long pipeline can tell a verification made for the current request
+    /// from a stale one. Set on every response returned by the verify
+    /// functions.
+    #[serde(skip)]
+    pub verified_at: Option<std::time::Instant>,
+}
+
+/// The `metadata` object of a siteverify response.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ResponseMetadata {
+    #[serde(default)]
+    pub interactive: Option<bool>,
+}
+
+impl SiteVerifyResponse {
+    /// Whether the visitor had to solve an interactive challenge (`true`) or
+    /// passed without one (`false`); `None` when Cloudflare didn't say.
+    pub fn interactive(&self) -> Option<bool> {
+        self.metadata.as_ref()?.interactive
+    }
+
+    /// `error_codes` as a set, for membership tests in custom checks such
+    /// as `codes.contains(&TurnstileErrorCode::TimeoutOrDuplicate)`.
+    pub fn error_code_set(&self) -> HashSet<TurnstileErrorCode> {
+        self.error_codes
+            .iter()
+            .map(|code| TurnstileErrorCode::from(code.as_str()))
+            .collect()
+    }
+
+    /// `challenge_ts` parsed as a point in time, if present and valid RFC 3339.
+    #[cfg(any(feature = "chrono", feature = "time"))]
+    pub fn challenge_time(&self) -> Option<std::time::SystemTime> {
+        parse_rfc3339(self.challenge_ts.as_deref()?)
+    }
+}
+
+#[cfg(feature = "chrono")]
+fn parse_rfc3339(ts: &str) -> Option<std::time::SystemTime> {
+    chrono::DateTime::parse_from_rfc3339(ts)
+        .ok()
+        .map(std::time::SystemTime::from)
+}
+
+#[cfg(all(feature = "time", not(feature = "chrono")))]
+fn parse_rfc3339(ts: &str) -> Option<std::time::SystemTime> {
+    time::OffsetDateTime::parse(ts, &time::format_description::well_known::Rfc3339)
+        .ok()
+        .map(std::time::SystemTime::from)
+}
+
+/// Verifies `token` against siteverify and returns the `success` flag.
+///
+/// The returned future is `Send` (as long as [`TurnstileConfig`] stays
+/// `Sync`), so it can be spawned on a work-stealing runtime such as
+/// `tokio::spawn`. Only the actix middleware future itself is `!Send`, which
+/// is inherent to actix services running on a single-threaded worker.
 pub async fn verify_cloudflare_turnstile(
     token: &str,
-    remoteip: &str,
+    remoteip: Option<&str>,
     config: &TurnstileConfig,
-) -> Result<bool, reqwest::Error> {
-    let body = json!({
-        "secret": config.secret_key,
+) -> Result<bool, TurnstileError> {
+    verify_cloudflare_turnstile_full(token, remoteip, config)
+        .await
+        .map(|outcome| outcome.success)
+}
+
+/// Like [`verify_cloudflare_turnstile`], but returns the whole siteverify
+/// response instead of just the `success` flag.
+///
+/// `remoteip` is optional for Cloudflare; it is left out of the request when
+/// `None` (e.g. for clients connected over a Unix domain socket).
+///
+/// With `config.fallback_secret_keys` set, the secrets are tried in order
+/// until one accepts the token; [`SiteVerifyResponse::secret_index`] tells
+/// which. If none does, the primary secret's response is returned. Errors end
+/// the attempt right away.
+///
+/// Network errors are retried up to `config.retries` times. Note that a
+/// request which timed out may still have reached Cloudflare, in which case
+/// the retry reports the token as `timeout-or-duplicate`.
+///
+/// The reqwest client and the retry backoff need a Tokio runtime, as actix
+/// provides. Polled on another executor, this fails with
+/// [`TurnstileError::RuntimeUnavailable`] instead of panicking; set an
+/// [`HttpBackend`] built on a client that runs there.
+pub async fn verify_cloudflare_turnstile_full(
+    token: &str,
+    remoteip: Option<&str>,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    let mut outcome = verify_keeping_raw(token, remoteip, config).await?;
+    if !config.retain_raw_response {
+        outcome.raw = None;
+    }
+    Ok(outcome)
+}
+
+/// Like [`verify_cloudflare_turnstile_full`], but also returns the body as
+/// Cloudflare sent it, e.g. for compliance logging, regardless of
+/// `config.retain_raw_response`.
+pub async fn verify_cloudflare_turnstile_raw(
+    token: &str,
+    remoteip: Option<&str>,
+    config: &TurnstileConfig,
+) -> Result<(SiteVerifyResponse, serde_json::Value), TurnstileError> {
+    let mut outcome = verify_keeping_raw(token, remoteip, config).await?;
+    let raw = outcome.raw.take().unwrap_or_default();
+    if config.retain_raw_response {
+        outcome.raw = Some(raw.clone());
+    }
+    Ok((outcome, raw))
+}
+
+async fn verify_keeping_raw(
+    token: &str,
+    remoteip: Option<&str>,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    // held across secrets and retries, which all count as one verification
+    let _permit = match &config.max_concurrent_verifications {
+        Some(limit) => Some(limit.acquire().await?),
+        None => None,
+    };
+    let secrets = std::iter::once(&config.secret_key).chain(&config.fallback_secret_keys);
+
+    let mut primary_failure = None;
+    for (index, secret) in secrets.enumerate() {
+        let mut outcome = verify_with_secret(token, secret, remoteip, config).await?;
+        outcome.secret_index = index;
+        outcome.verified_at = Some(std::time::Instant::now());
+        if outcome.success {
+            return Ok(outcome);
+        }
+        primary_failure.get_or_insert(outcome);
+    }
+
+    let mut outcome = primary_failure.expect("at least the primary secret is tried");
+    override_success(&mut outcome, config);
+    Ok(outcome)
+}
+
+/// Applies `treat_as_success_codes` to a failed response.
+fn override_success(outcome: &mut SiteVerifyResponse, config: &TurnstileConfig) {
+    let overridable = !outcome.error_codes.is_empty()
+        && outcome.error_codes.iter().all(|code| {
+            !SECRET_ERROR_CODES.contains(&code.as_str())
+                && config.treat_as_success_codes.contains(code)
+        });
+    if !overridable {
+        return;
+    }
+
+    #[cfg(feature = "log")]
+    log::warn!(
+        "turnstile accepting failed verification ({}) per treat_as_success_codes",
+        outcome.error_codes.join(", ")
+    );
+    outcome.success = true;
+    outcome.success_overridden = true;
+}
+
+async fn verify_with_secret(
+    token: &str,
+    secret: &str,
+    remoteip: Option<&str>,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    let mut body = json!({
+        "secret": secret,
         "response": token,
-        "remoteip": remoteip
     });
+    if let Some(remoteip) = remoteip {
+        body["remoteip"] = json!(remoteip);
+    }
+    if let Some(action) = &config.report_action {
+        body["action"] = json!(action);
+    }
+
+    let mut attempt = 0;
+    loop {
+        let call = send_siteverify(&body, config);
+        #[cfg(feature = "opentelemetry")]
+        let call = crate::otel::client_span(config, call);
+        match call.await {
+            // a 429 isn't retried right away; the caller sees `retry_after`
+            Err(TurnstileError::NetworkError(_) | TurnstileError::BackendError(_))
+                if attempt < config.retries =>
+            {
+                attempt += 1;
+                actix_web::rt::time::sleep(backoff_delay(
+                    config.retry_backoff,
+                    config.retry_jitter,
+                ))
+                .await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The pause before the next retry attempt.
+fn backoff_delay(backoff: Duration, jitter: RetryJitter) -> Duration {
+    let random = || crate::random_u64() as f64 / u64::MAX as f64;
+
+    match jitter {
+        RetryJitter::None => backoff,
+        RetryJitter::Full => backoff.mul_f64(random()),
+        RetryJitter::Equal => backoff / 2 + (backoff / 2).mul_f64(random()),
+    }
+}
+
+async fn send_siteverify(
+    body: &serde_json::Value,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    if let Some(backend) = &config.http_backend {
+        return send_via_backend(backend.as_ref(), body, config).await;
+    }
+
+    // reqwest panics deep inside hyper without a reactor
+    if tokio::runtime::Handle::try_current().is_err() {
+        return Err(TurnstileError::RuntimeUnavailable);
+    }
+    let client = match (&config.rotating_client, &config.client) {
+        (Some(rotating), _) => rotating.client(),
+        (None, Some(client)) => client.clone(),
+        (None, None) => reqwest_client::shared_client(config),
+    };
+
+    let mut req = client.post(&config.verify_url).json(body);
+    if let Some(secs) = config.timeout_secs {
+        req = req.timeout(Duration::from_secs(secs));
+    }
+
+    let resp = req.send().await?;
+    #[cfg(feature = "opentelemetry")]
+    crate::otel::record_status(resp.status().as_u16());
+
+    if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = parse_retry_after(
+            resp.headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok()),
+        );
+        return Err(TurnstileError::RateLimited { retry_after });
+    }
+
+    if resp.status().is_success() {
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok());
+        check_content_type(content_type)?;
+    }
+
+    let headers = resp
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)));
+    let captured_headers = capture_headers(headers, &config.captured_response_headers);
+
+    // a non-JSON body is malformed, unless it's a proxy's error page (which
+    // stays a retryable network error)
+    let status_error = resp.error_for_status_ref().err();
+    let body = resp.bytes().await?;
+    let json = serde_json::from_slice(&body).map_err(|err| match status_error {
+        Some(status_error) => TurnstileError::NetworkError(status_error),
+        None => TurnstileError::MalformedResponse(err),
+    })?;
+    let mut outcome = decode_outcome(json, config)?;
+    outcome.captured_headers = captured_headers;
+    Ok(outcome)
+}
+
+async fn send_via_backend(
+    backend: &dyn HttpBackend,
+    body: &serde_json::Value,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    let resp = backend
+        .post_json(&config.verify_url, body)
+        .await
+        .map_err(TurnstileError::BackendError)?;
+    #[cfg(feature = "opentelemetry")]
+    crate::otel::record_status(resp.status);
+
+    let headers = || {
+        resp.headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    };
+
+    if resp.status == StatusCode::TOO_MANY_REQUESTS.as_u16() {
+        let retry_after = headers()
+            .find(|(name, _)| name.eq_ignore_ascii_case(RETRY_AFTER.as_str()))
+            .map(|(_, value)| value);
+        return Err(TurnstileError::RateLimited {
+            retry_after: parse_retry_after(retry_after),
+        });
+    }
+
+    if (200..300).contains(&resp.status) {
+        check_content_type(
+            headers()
+                .find(|(name, _)| name.eq_ignore_ascii_case(CONTENT_TYPE.as_str()))
+                .map(|(_, value)| value),
+        )?;
+    }
+
+    let json = serde_json::from_str(&resp.body).map_err(|err| {
+        if (200..300).contains(&resp.status) {
+            TurnstileError::MalformedResponse(err)
+        } else {
+            TurnstileError::BackendError(Box::new(err))
+        }
+    })?;
+    let mut outcome = decode_outcome(json, config)?;
+    outcome.captured_headers = capture_headers(headers(), &config.captured_response_headers);
+    Ok(outcome)
+}
+
+/// Decodes a siteverify body, applying `error_codes_field` and
+/// `treat_missing_success_as` to bodies without `success` (counting `null`
+/// as missing). The body is kept on [`SiteVerifyResponse::raw`] as
+/// received, except for error codes beyond [`MAX_ERROR_CODES`].
+fn decode_outcome(
+    mut json: serde_json::Value,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    cap_error_codes(&mut json, config);
+    let mut outcome = decode_json(Cow::Borrowed(&json), config)?;
+    outcome.raw = Some(json);
+    Ok(outcome)
+}
+
+/// Error codes kept from a response; a real one carries a handful, so any
+/// beyond this come from a broken or hostile upstream.
+const MAX_ERROR_CODES: usize = 64;
+
+fn cap_error_codes(json: &mut serde_json::Value, config: &TurnstileConfig) {
+    for field in ["error-codes", "error_codes", &config.error_codes_field] {
+        if let Some(codes) = json
+            .get_mut(field)
+            .and_then(serde_json::Value::as_array_mut)
+        {
+            if codes.len() > MAX_ERROR_CODES {
+                #[cfg(feature = "log")]
+                log::warn!(
+                    "siteverify response has {} error codes, keeping the first {}",
+                    codes.len(),
+                    MAX_ERROR_CODES
+                );
+                codes.truncate(MAX_ERROR_CODES);
+            }
+        }
+    }
+}
+
+/// Only copies `json` when `error_codes_field` or `treat_missing_success_as`
+/// have it rewritten.
+fn decode_json(
+    mut json: Cow<'_, serde_json::Value>,
+    config: &TurnstileConfig,
+) -> Result<SiteVerifyResponse, TurnstileError> {
+    if config.error_codes_field != "error-codes" && json.get(&config.error_codes_field).is_some() {
+        if let Some(body) = json.to_mut().as_object_mut() {
+            if let Some(codes) = body.remove(&config.error_codes_field) {
+                body.insert("error-codes".to_string(), codes);
+            }
+        }
+    }
+    let missing_success = json.get("success").is_none_or(serde_json::Value::is_null);
+    if config.treat_missing_success_as == MissingSuccess::Failure && missing_success {
+        if let Some(body) = json.to_mut().as_object_mut() {
+            body.insert("success".to_string(), false.into());
+        }
+    }
+    let strict_error = match SiteVerifyResponse::deserialize(&*json) {
+        Ok(outcome) => return Ok(outcome),
+        Err(err) => err,
+    };
+    // an unexpected type elsewhere in the body mustn't turn a pass into a
+    // failure; only a missing or non-boolean `success` is fatal
+    let Some(outcome) = lenient_outcome(&json) else {
+        return Err(TurnstileError::MalformedResponse(strict_error));
+    };
+    #[cfg(feature = "log")]
+    log::warn!(
+        "siteverify response didn't decode strictly, ignoring mistyped fields: {}",
+        strict_error
+    );
+    Ok(outcome)
+}
+
+/// Picks the fields out of a body that doesn't match [`SiteVerifyResponse`],
+/// leaving any field of an unexpected type unset.
+fn lenient_outcome(json: &serde_json::Value) -> Option<SiteVerifyResponse> {
+    let string = |key: &str| json.get(key)?.as_str().map(str::to_owned);
+    let error_codes = json
+        .get("error-codes")
+        .or_else(|| json.get("error_codes"))
+        .and_then(serde_json::Value::as_array)
+        .map(|codes| {
+            codes
+                .iter()
+                .filter_map(|code| code.as_str().map(str::to_owned))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(SiteVerifyResponse {
+        success: json.get("success")?.as_bool()?,
+        error_codes,
+        challenge_ts: string("challenge_ts"),
+        hostname: string("hostname"),
+        action: string("action"),
+        cdata: string("cdata"),
+        score: json.get("score").and_then(serde_json::Value::as_f64),
+        metadata: json
+            .get("metadata")
+            .and_then(|metadata| ResponseMetadata::deserialize(metadata).ok()),
+        ..Default::default()
+    })
+}
+
+/// Rejects non-JSON `2xx` replies up front, so an HTML error page served
+/// with `200 OK` isn't reported as a decoding failure. A missing
+/// `Content-Type` is let through to the JSON decoder. Error statuses aren't
+/// checked, so a gateway's HTML error page stays a retryable network error.
+fn check_content_type(content_type: Option<&str>) -> Result<(), TurnstileError> {
+    let Some(content_type) = content_type else {
+        return Ok(());
+    };
+
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    let is_json = mime.eq_ignore_ascii_case("application/json")
+        || mime.to_ascii_lowercase().ends_with("+json");
+    if is_json {
+        Ok(())
+    } else {
+        Err(TurnstileError::UpstreamUnavailable(content_type.to_owned()))
+    }
+}
+
+/// Retry-After may also be an HTTP-date; only the delay-seconds form is kept.
+fn parse_retry_after(value: Option<&str>) -> Option<Duration> {
+    value
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+fn capture_headers<'a>(
+    headers: impl Iterator<Item = (&'a str, &'a str)>,
+    prefixes: &[String],
+) -> HashMap<String, String> {
+    if prefixes.is_empty() {
+        return HashMap::new();
+    }
+
+    headers
+        .map(|(name, value)| (name.to_ascii_lowercase(), value))
+        .filter(|(name, _)| {
+            prefixes
+                .iter()
+                .any(|prefix| name.starts_with(&prefix.to_ascii_lowercase()))
+        })
+        .map(|(name, value)| (name, value.to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+
+    use super::*;
+    use crate::test_support::spawn_siteverify_stub;
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    fn arbitrary_json() -> impl proptest::strategy::Strategy<Value = serde_json::Value> {
+        use proptest::prelude::*;
+
+        let leaf = prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::from),
+            any::<i64>().prop_map(serde_json::Value::from),
+            any::<f64>().prop_map(serde_json::Value::from),
+            ".*".prop_map(serde_json::Value::from),
+        ];
+        leaf.prop_recursive(4, 32, 6, |inner| {
+            let keys = prop_oneof![
+                Just("success".to_string()),
+                Just("error-codes".to_string()),
+                Just("hostname".to_string()),
+                Just("challenge_ts".to_string()),
+                ".*",
+            ];
+            prop_oneof![
+                proptest::collection::vec(inner.clone(), 0..4).prop_map(serde_json::Value::from),
+                proptest::collection::btree_map(keys, inner, 0..6)
+                    .prop_map(|map| serde_json::Value::Object(map.into_iter().collect())),
+            ]
+        })
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_decoding_arbitrary_json_never_panics(json in arbitrary_json()) {
+            let config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+            // a body decodes exactly when it has a boolean `success`, which
+            // the outcome reflects; anything else is malformed
+            match decode_outcome(json.clone(), &config) {
+                Ok(outcome) => {
+                    proptest::prop_assert_eq!(Some(outcome.success), json["success"].as_bool());
+                }
+                Err(err) => {
+                    proptest::prop_assert!(matches!(err, TurnstileError::MalformedResponse(_)));
+                    proptest::prop_assert_eq!(json["success"].as_bool(), None);
+                }
+            }
+        }
+
+        #[test]
+        fn test_false_is_decoded_not_malformed(
+            codes in proptest::collection::vec(".*", 0..4),
+            hostname in proptest::option::of(".*"),
+        ) {
+            let json = serde_json::json!({
+                "success": false,
+                "error-codes": codes,
+                "hostname": hostname,
+            });
+            let config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+            let outcome = decode_outcome(json, &config).unwrap();
+            proptest::prop_assert!(!outcome.success);
+            proptest::prop_assert_eq!(outcome.error_codes, codes);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_treat_as_success_codes() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.treat_as_success_codes = vec![
+            "timeout-or-duplicate".to_string(),
+            "invalid-input-secret".to_string(),
+        ];
+
+        config.verify_url = spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error-codes": ["timeout-or-duplicate"]}"#,
+        );
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert!(outcome.success);
+        assert!(outcome.success_overridden);
+        assert_eq!(outcome.error_codes, ["timeout-or-duplicate"]);
+
+        // only if every code is listed, and never for secret codes
+        for body in [
+            r#"{"success": false, "error-codes": ["timeout-or-duplicate", "bad-request"]}"#,
+            r#"{"success": false, "error-codes": ["invalid-input-secret"]}"#,
+            r#"{"success": false, "error-codes": []}"#,
+        ] {
+            config.verify_url = spawn_siteverify_stub(StatusCode::OK, vec![], body);
+            let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+                .await
+                .unwrap();
+            assert!(!outcome.success, "{} was accepted", body);
+            assert!(!outcome.success_overridden);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_treat_missing_success_as() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"error-codes": ["internal-error"]}"#,
+        );
+
+        let err = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .expect_err("missing success must be malformed");
+        assert!(matches!(err, TurnstileError::MalformedResponse(_)));
+        assert!(err.is_upstream());
+
+        config.treat_missing_success_as = MissingSuccess::Failure;
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert!(!outcome.success);
+        assert_eq!(outcome.error_codes, ["internal-error"]);
+    }
+
+    #[actix_web::test]
+    async fn test_mistyped_fields_keep_success() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": true, "hostname": {"name": "example.com"}, "action": "login", "score": "high"}"#,
+        );
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert!(outcome.success);
+        assert_eq!(outcome.hostname, None);
+        assert_eq!(outcome.action.as_deref(), Some("login"));
+        assert_eq!(outcome.score, None);
+
+        config.verify_url = spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error-codes": ["invalid-input-response", 7], "cdata": 1}"#,
+        );
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert!(!outcome.success);
+        assert_eq!(outcome.error_codes, ["invalid-input-response"]);
+
+        // `success` itself still has to be a boolean
+        config.verify_url = spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": "true", "hostname": 1}"#,
+        );
+        let err = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TurnstileError::MalformedResponse(_)));
+    }
+
+    #[actix_web::test]
+    async fn test_verified_at() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(StatusCode::OK, vec![], r#"{"success": true}"#);
+
+        let before = std::time::Instant::now();
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        let verified_at = outcome.verified_at.unwrap();
+        assert!(verified_at >= before && verified_at <= std::time::Instant::now());
+    }
+
+    #[actix_web::test]
+    async fn test_error_codes_field() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "error_codes": ["invalid-input-response"]}"#,
+        );
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert_eq!(outcome.error_codes, ["invalid-input-response"]);
+
+        config.error_codes_field = "errors".to_string();
+        config.verify_url = spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![],
+            r#"{"success": false, "errors": ["timeout-or-duplicate"]}"#,
+        );
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert_eq!(outcome.error_codes, ["timeout-or-duplicate"]);
+    }
+
+    #[actix_web::test]
+    async fn test_raw_response() {
+        let body =
+            r#"{"success": true, "hostname": "example.com", "metadata": {"ephemeral_id": "x:1"}}"#;
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(StatusCode::OK, vec![], body);
+
+        let (outcome, raw) = verify_cloudflare_turnstile_raw("token", None, &config)
+            .await
+            .unwrap();
+        assert!(outcome.success);
+        assert_eq!(
+            raw,
+            serde_json::from_str::<serde_json::Value>(body).unwrap()
+        );
+        assert_eq!(raw["metadata"]["ephemeral_id"], "x:1");
+        assert!(outcome.raw.is_none());
+
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert!(outcome.raw.is_none());
+
+        config.retain_raw_response = true;
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert_eq!(outcome.raw, Some(raw));
+    }
+
+    #[actix_web::test]
+    async fn test_error_codes_are_capped() {
+        let codes = vec!["invalid-input-response"; 10_000];
+        let body = json!({ "success": false, "error-codes": codes }).to_string();
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(StatusCode::OK, vec![], body.leak());
+
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert!(!outcome.success);
+        assert_eq!(outcome.error_codes.len(), MAX_ERROR_CODES);
+
+        // the retained body is capped as well
+        config.retain_raw_response = true;
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        let raw = outcome.raw.unwrap();
+        assert_eq!(
+            raw["error-codes"].as_array().unwrap().len(),
+            MAX_ERROR_CODES
+        );
+        let (_, raw) = verify_cloudflare_turnstile_raw("token", None, &config)
+            .await
+            .unwrap();
+        assert_eq!(
+            raw["error-codes"].as_array().unwrap().len(),
+            MAX_ERROR_CODES
+        );
+    }
+
+    #[test]
+    fn test_error_code_set() {
+        let outcome: SiteVerifyResponse = serde_json::from_str(
+            r#"{"success": false, "error-codes": ["timeout-or-duplicate", "new-code", "timeout-or-duplicate"]}"#,
+        )
+        .unwrap();
+        let codes = outcome.error_code_set();
+        assert_eq!(codes.len(), 2);
+        assert!(codes.contains(&TurnstileErrorCode::TimeoutOrDuplicate));
+        assert!(codes.contains(&TurnstileErrorCode::Other("new-code".to_string())));
+        assert!(!codes.contains(&TurnstileErrorCode::InternalError));
+
+        for code in ["invalid-input-secret", "bad-request", "new-code"] {
+            assert_eq!(TurnstileErrorCode::from(code).as_str(), code);
+        }
+    }
+
+    #[test]
+    fn test_interactive_indicator() {
+        let decode = |body| serde_json::from_str::<SiteVerifyResponse>(body).unwrap();
+        let outcome = decode(r#"{"success": true, "metadata": {"interactive": true}}"#);
+        assert_eq!(outcome.interactive(), Some(true));
+        let outcome = decode(r#"{"success": true, "metadata": {"interactive": false}}"#);
+        assert_eq!(outcome.interactive(), Some(false));
+        let outcome = decode(r#"{"success": true, "metadata": {"ephemeral_id": "x:1"}}"#);
+        assert_eq!(outcome.interactive(), None);
+        assert_eq!(decode(r#"{"success": true}"#).interactive(), None);
+    }
+
+    #[test]
+    fn test_missing_success_is_malformed() {
+        for body in [
+            r#"{}"#,
+            r#"{"success": "true"}"#,
+            r#"{"success": null}"#,
+            r#"[]"#,
+        ] {
+            assert!(
+                serde_json::from_str::<SiteVerifyResponse>(body).is_err(),
+                "{} decoded",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_futures_are_send() {
+        assert_send_sync::<TurnstileConfig>();
+
+        let config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        assert_send(&verify_cloudflare_turnstile(
+            "token",
+            Some("192.168.1.1"),
+            &config,
+        ));
+        assert_send(&verify_cloudflare_turnstile_full(
+            "token",
+            Some("192.168.1.1"),
+            &config,
+        ));
+    }
+
+    #[cfg(any(feature = "chrono", feature = "time"))]
+    #[test]
+    fn test_challenge_time_parsing() {
+        let outcome = SiteVerifyResponse {
+            challenge_ts: Some("2022-02-28T15:14:30.096Z".to_string()),
+            ..Default::default()
+        };
+        let expected = std::time::UNIX_EPOCH + Duration::from_millis(1_646_061_270_096);
+        assert_eq!(outcome.challenge_time(), Some(expected));
+
+        let outcome = SiteVerifyResponse {
+            challenge_ts: Some("yesterday".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(outcome.challenge_time(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_compressed_response_is_decoded() {
+        let body = r#"{"success": true, "hostname": "example.com"}"#;
+
+        for encoding in ["gzip", "br"] {
+            let url = crate::test_support::spawn_compressed_siteverify_stub(body);
+
+            // make sure the stub really compresses before relying on it
+            let raw = reqwest::Client::builder()
+                .no_gzip()
+                .no_brotli()
+                .build()
+                .unwrap()
+                .post(&url)
+                .header("accept-encoding", encoding)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(raw.headers()["content-encoding"], encoding);
+
+            let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+            config.verify_url = url;
+            config.client = Some(crate::reqwest_client::build_client(Some(5)));
+
+            let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+                .await
+                .unwrap();
+            assert!(outcome.success);
+            assert_eq!(outcome.hostname.as_deref(), Some("example.com"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_report_action_is_sent() {
+        let (url, requests) =
+            crate::test_support::spawn_recording_siteverify_stub(r#"{"success": true}"#);
+
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = url;
+        verify_cloudflare_turnstile("token", None, &config)
+            .await
+            .unwrap();
+
+        config.report_action = Some("checkout".to_string());
+        verify_cloudflare_turnstile("token", None, &config)
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].get("action").is_none());
+        assert_eq!(requests[1]["action"], "checkout");
+    }
+
+    #[actix_web::test]
+    async fn test_fallback_secrets() {
+        let (url, requests) = crate::test_support::spawn_secret_checking_siteverify_stub("new");
+
+        let mut config = TurnstileConfig::new("old");
+        config.verify_url = url;
+        config.fallback_secret_keys = vec!["new".to_string(), "unused".to_string()];
+
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert!(outcome.success);
+        assert_eq!(outcome.secret_index, 1);
+
+        // short-circuits once a secret matched
+        let secrets: Vec<_> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|body| body["secret"].clone())
+            .collect();
+        assert_eq!(secrets, ["old", "new"]);
+
+        config.fallback_secret_keys = vec!["other".to_string()];
+        let outcome = verify_cloudflare_turnstile_full("token", None, &config)
+            .await
+            .unwrap();
+        assert!(!outcome.success);
+        assert_eq!(outcome.secret_index, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_html_response_is_upstream_unavailable() {
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![("content-type", "text/html; charset=UTF-8")],
+            "<html><body>We're having issues</body></html>",
+        );
+
+        match verify_cloudflare_turnstile("token", None, &config).await {
+            Err(TurnstileError::UpstreamUnavailable(content_type)) => {
+                assert_eq!(content_type, "text/html; charset=UTF-8");
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limited_with_retry_after() {
+        let url = spawn_siteverify_stub(
+            StatusCode::TOO_MANY_REQUESTS,
+            vec![("retry-after", "30")],
+            "",
+        );
+
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = url;
+
+        match verify_cloudflare_turnstile("token", Some("192.168.1.1"), &config).await {
+            Err(TurnstileError::RateLimited { retry_after }) => {
+                assert_eq!(retry_after, Some(Duration::from_secs(30)));
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limited_without_retry_after() {
+        let url = spawn_siteverify_stub(StatusCode::TOO_MANY_REQUESTS, vec![], "");
+
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = url;
+
+        match verify_cloudflare_turnstile("token", Some("192.168.1.1"), &config).await {
+            Err(TurnstileError::RateLimited { retry_after }) => assert_eq!(retry_after, None),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_captures_selected_response_headers() {
+        let url = spawn_siteverify_stub(
+            StatusCode::OK,
+            vec![
+                ("cf-ratelimit-remaining", "42"),
+                ("cf-ratelimit-reset", "60"),
+                ("x-unrelated", "1"),
+            ],
+            r#"{"success": true}"#,
+        );
+
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = url.clone();
+
+        let outcome = verify_cloudflare_turnstile_full("token", Some("192.168.1.1"), &config)
+            .await
+            .unwrap();
+        assert!(outcome.captured_headers.is_empty());
+
+        config.captured_response_headers = vec!["CF-RateLimit-".to_string()];
+        let outcome = verify_cloudflare_turnstile_full("token", Some("192.168.1.1"), &config)
+            .await
+            .unwrap();
+        assert_eq!(outcome.captured_headers.len(), 2);
+        assert_eq!(outcome.captured_headers["cf-ratelimit-remaining"], "42");
+        assert_eq!(outcome.captured_headers["cf-ratelimit-reset"], "60");
+    }
+
+    #[test]
+    fn test_backoff_jitter_bounds() {
+        let backoff = Duration::from_millis(200);
+        assert_eq!(backoff_delay(backoff, RetryJitter::None), backoff);
+
+        for _ in 0..100 {
+            assert!(backoff_delay(backoff, RetryJitter::Full) <= backoff);
+
+            let equal = backoff_delay(backoff, RetryJitter::Equal);
+            assert!(equal >= backoff / 2 && equal <= backoff);
+        }
+
+        // not stuck on a single value
+        let samples: std::collections::HashSet<_> = (0..20)
+            .map(|_| backoff_delay(backoff, RetryJitter::Full))
+            .collect();
+        assert!(samples.len() > 1);
+    }
+
+    #[actix_web::test]
+    async fn test_retries_network_errors() {
+        let url = crate::test_support::spawn_sequenced_siteverify_stub(vec![
+            (StatusCode::BAD_GATEWAY, "<html>bad gateway</html>"),
+            (StatusCode::BAD_GATEWAY, "<html>bad gateway</html>"),
+            (StatusCode::OK, r#"{"success": true}"#),
+        ]);
+
+        let mut config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        config.verify_url = url.clone();
+        config.retry_backoff = Duration::from_millis(10);
+
+        config.retries = 1;
+        assert!(matches!(
+            verify_cloudflare_turnstile("token", Some("192.168.1.1"), &config).await,
+            Err(TurnstileError::NetworkError(_))
+        ));
 
-    let resp = REQWEST_CLIENT
-        .post("https://challenges.cloudflare.com/turnstile/v0/siteverify")
-        .json(&body)
-        .send()
-        .await?;
+        // the stub has served its two failures by now
+        config.retries = 2;
+        assert!(
+            verify_cloudflare_turnstile("token", Some("192.168.1.1"), &config)
+                .await
+                .unwrap()
+        );
+    }
 
-    let js: Value = resp.json().await?;
+    #[test]
+    fn test_outside_tokio_runtime() {
+        use futures_util::FutureExt;
 
-    Ok(js["success"].as_bool().unwrap_or(false))
+        let config = TurnstileConfig::new("1x0000000000000000000000000000000AA");
+        let result = verify_cloudflare_turnstile("token", None, &config).now_or_never();
+        assert!(matches!(
+            result,
+            Some(Err(TurnstileError::RuntimeUnavailable))
+        ));
+    }
 }
@@ -0,0 +1,190 @@
+//! A signed cookie set after a successful verification, letting the client
+//! skip verification until it expires ("challenge once").
+//!
+//! The cookie value is `<expiry>.<signature>`: the expiry as Unix seconds,
+//! and the lowercase hex HMAC-SHA256 of that decimal string under the
+//! configured key. It isn't bound to the client, so anyone holding the
+//! cookie skips verification until it expires; keep the TTL short.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::ServiceRequest,
+};
+use sha2::{Digest, Sha256};
+
+/// Settings for [`TurnstileConfig::verified_cookie`](crate::TurnstileConfig::verified_cookie).
+#[derive(Clone)]
+pub struct VerifiedCookie {
+    pub name: String,
+    /// HMAC key; at least 32 random bytes.
+    pub key: Vec<u8>,
+    /// How long the cookie lets the client skip verification.
+    pub ttl: Duration,
+}
+
+impl std::fmt::Debug for VerifiedCookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VerifiedCookie")
+            .field("name", &self.name)
+            .field("key", &"<redacted>")
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl VerifiedCookie {
+    pub fn new(name: impl Into<String>, key: impl Into<Vec<u8>>, ttl: Duration) -> Self {
+        Self {
+            name: name.into(),
+            key: key.into(),
+            ttl,
+        }
+    }
+
+    /// A fresh cookie, valid for `ttl` from now: `HttpOnly`, `Secure`,
+    /// `SameSite=Lax` and scoped to `/`.
+    pub fn issue(&self) -> Cookie<'static> {
+        let expiry = unix_secs(SystemTime::now() + self.ttl);
+        let value = format!("{}.{}", expiry, self.sign(expiry));
+        Cookie::build(self.name.clone(), value)
+            .path("/")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Lax)
+            .max_age(
+                self.ttl
+                    .try_into()
+                    .unwrap_or(actix_web::cookie::time::Duration::MAX),
+            )
+            .finish()
+    }
+
+    /// Whether `req` carries an unexpired cookie signed with the key.
+    pub fn is_valid(&self, req: &ServiceRequest) -> bool {
+        req.cookie(&self.name)
+            .is_some_and(|cookie| self.verify(cookie.value(), SystemTime::now()))
+    }
+
+    fn verify(&self, value: &str, now: SystemTime) -> bool {
+        let Some((expiry, signature)) = value.split_once('.') else {
+            return false;
+        };
+        let Ok(expiry) = expiry.parse::<u64>() else {
+            return false;
+        };
+        // compared in constant time, so the signature can't be guessed byte by byte
+        let expected = self.sign(expiry);
+        let signed = expected.len() == signature.len()
+            && expected
+                .bytes()
+                .zip(signature.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0;
+        signed && expiry > unix_secs(now)
+    }
+
+    fn sign(&self, expiry: u64) -> String {
+        hmac_sha256(&self.key, expiry.to_string().as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// HMAC-SHA256 as in RFC 2104.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test cases 2 and 6
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_issued_cookie_is_valid_until_expiry() {
+        let config = VerifiedCookie::new(
+            "ts_ok",
+            *b"0123456789abcdef0123456789abcdef",
+            Duration::from_secs(60),
+        );
+        let cookie = config.issue();
+        assert!(cookie.http_only().unwrap());
+        assert_eq!(cookie.max_age().unwrap().whole_seconds(), 60);
+
+        let req = TestRequest::default()
+            .cookie(cookie.clone())
+            .to_srv_request();
+        assert!(config.is_valid(&req));
+
+        let later = SystemTime::now() + Duration::from_secs(61);
+        assert!(!config.verify(cookie.value(), later));
+    }
+
+    #[test]
+    fn test_tampered_cookie_is_invalid() {
+        let config = VerifiedCookie::new(
+            "ts_ok",
+            *b"0123456789abcdef0123456789abcdef",
+            Duration::from_secs(60),
+        );
+        let value = config.issue().value().to_owned();
+        let (expiry, signature) = value.split_once('.').unwrap();
+        let now = SystemTime::now();
+
+        let extended = format!("{}.{}", expiry.parse::<u64>().unwrap() + 3600, signature);
+        assert!(!config.verify(&extended, now));
+        let other_key = VerifiedCookie::new("ts_ok", *b"another key", Duration::from_secs(60));
+        assert!(!other_key.verify(&value, now));
+        for garbage in ["", "123", "abc.def", &format!("{}.", expiry)] {
+            assert!(!config.verify(garbage, now));
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use actix_web::{test, web, App, HttpResponse};
+use turnstile_actix_web::{Turnstile, TurnstileConfig};
+
+mod common;
+
+/// Local siteverify stand-in that accepts exactly one token.
+fn spawn_stub() -> String {
+    common::serve(|| {
+        App::new().default_service(web::to(|body: web::Json<serde_json::Value>| async move {
+            let success = body["response"] == "stub-accepted-token";
+            HttpResponse::Ok().json(serde_json::json!({ "success": success }))
+        }))
+    })
+}
+
+#[actix_web::test]
+async fn test_from_env_selects_stub_endpoint() {
+    std::env::set_var("TURNSTILE_SECRET_KEY", "stub-secret");
+    std::env::set_var("TURNSTILE_VERIFY_URL", spawn_stub());
+
+    let config = TurnstileConfig::from_env().unwrap();
+    assert_eq!(config.secret_key, "stub-secret");
+
+    let app = test::init_service(
+        App::new()
+            .wrap(Turnstile::new(config))
+            .service(web::resource("/").to(HttpResponse::Ok)),
+    )
+    .await;
+
+    for (token, accepted) in [("stub-accepted-token", true), ("other-token", false)] {
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("cf-turnstile-response", token))
+            .peer_addr("192.168.1.1:12345".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().is_success(), accepted, "token {}", token);
+    }
+}
@@ -0,0 +1,121 @@
+//! Middleware tests over real sockets, covering what `test::init_service`
+//! bypasses: peer addresses, forwarding headers and the wire format.
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::{http::StatusCode, web, App, HttpResponse};
+use turnstile_actix_web::{
+    client_ip::{IpSource, IpSourceConfig},
+    Turnstile, TurnstileConfig,
+};
+
+mod common;
+
+type RemoteIps = Arc<Mutex<Vec<Option<String>>>>;
+
+/// Local siteverify stand-in accepting `stub-accepted-token` and recording
+/// the `remoteip` of every request.
+fn spawn_stub() -> (String, RemoteIps) {
+    let remote_ips = RemoteIps::default();
+
+    let recorded = remote_ips.clone();
+    let url = common::serve(move || {
+        let recorded = recorded.clone();
+        App::new().default_service(web::to(move |body: web::Json<serde_json::Value>| {
+            let remoteip = body["remoteip"].as_str().map(str::to_owned);
+            recorded.lock().unwrap().push(remoteip);
+            let success = body["response"] == "stub-accepted-token";
+            async move { HttpResponse::Ok().json(serde_json::json!({ "success": success })) }
+        }))
+    });
+
+    (url, remote_ips)
+}
+
+fn start(config: TurnstileConfig) -> actix_test::TestServer {
+    actix_test::start(move || {
+        App::new()
+            .wrap(Turnstile::new(config.clone()))
+            .service(web::resource("/").to(HttpResponse::Ok))
+    })
+}
+
+#[actix_web::test]
+async fn test_verifies_over_http() {
+    let (verify_url, remote_ips) = spawn_stub();
+    let mut config = TurnstileConfig::new("stub-secret");
+    config.verify_url = verify_url;
+    let srv = start(config);
+
+    let resp = srv
+        .get("/")
+        .insert_header(("cf-turnstile-response", "stub-accepted-token"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let mut resp = srv
+        .get("/")
+        .insert_header(("cf-turnstile-response", "other-token"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "captcha_verification_failed");
+
+    // the peer address of the socket was sent along
+    let remote_ips = remote_ips.lock().unwrap();
+    assert_eq!(remote_ips.len(), 2);
+    assert!(remote_ips
+        .iter()
+        .all(|ip| ip.as_deref() == Some("127.0.0.1")));
+}
+
+#[actix_web::test]
+async fn test_missing_token_over_http() {
+    let (verify_url, remote_ips) = spawn_stub();
+    let mut config = TurnstileConfig::new("stub-secret");
+    config.verify_url = verify_url;
+    let srv = start(config);
+
+    let resp = srv.post("/").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(remote_ips.lock().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn test_forwarded_client_ip_over_http() {
+    let (verify_url, remote_ips) = spawn_stub();
+    let mut config = TurnstileConfig::new("stub-secret");
+    config.verify_url = verify_url;
+    let real_ip = start(config.clone());
+    config.ip_source = IpSourceConfig {
+        sources: vec![IpSource::Peer],
+    };
+    let peer = start(config);
+
+    for srv in [&real_ip, &peer] {
+        let resp = srv
+            .get("/")
+            .insert_header(("cf-turnstile-response", "stub-accepted-token"))
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    assert_eq!(
+        *remote_ips.lock().unwrap(),
+        [
+            Some("203.0.113.7".to_string()),
+            Some("127.0.0.1".to_string())
+        ]
+    );
+}